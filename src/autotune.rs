@@ -0,0 +1,160 @@
+use std::f32::consts::PI;
+
+use crate::gains::Pid;
+
+// Oscillations measured per wheel, after the first one has settled
+const CYCLES: usize = 4;
+// Gives up when the loop doesn't oscillate
+const TIMEOUT_SECS: f32 = 20.0;
+// Noise band around the setpoint, as a fraction of it, stops the relay from chattering
+const HYSTERESIS: f32 = 0.05;
+
+/// Relay feedback experiment (Åström–Hägglund) on one wheel: the duty cycle switches
+/// between `bias ± amplitude` whenever the speed crosses the setpoint,
+/// which makes the speed oscillate at the ultimate period of the loop.
+pub struct RelayTuner {
+    // ticks per second
+    setpoint: f32,
+    bias: f32,
+    amplitude: f32,
+    hysteresis: f32,
+    high: bool,
+    elapsed: f32,
+    samples: u32,
+    // A full oscillation lasts from one switch to high to the next
+    cycle_start: Option<f32>,
+    max: f32,
+    min: f32,
+    periods: Vec<f32>,
+    amplitudes: Vec<f32>,
+}
+
+impl RelayTuner {
+    pub fn new(setpoint: f32, bias: f32, amplitude: f32) -> RelayTuner {
+        RelayTuner {
+            setpoint,
+            bias,
+            amplitude,
+            hysteresis: setpoint * HYSTERESIS,
+            high: true,
+            elapsed: 0.0,
+            samples: 0,
+            cycle_start: None,
+            max: 0.0,
+            min: 0.0,
+            periods: Vec::new(),
+            amplitudes: Vec::new(),
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let output = if self.high {
+            self.bias + self.amplitude
+        } else {
+            self.bias - self.amplitude
+        };
+        output.max(0.0).min(100.0)
+    }
+
+    /// Duty cycle for the next interval after `speed` was measured over `dt` seconds.
+    pub fn step(&mut self, speed: f32, dt: f32) -> f32 {
+        self.elapsed += dt;
+        self.samples += 1;
+        self.max = self.max.max(speed);
+        self.min = self.min.min(speed);
+
+        if self.high && speed > self.setpoint + self.hysteresis {
+            self.high = false;
+        } else if !self.high && speed < self.setpoint - self.hysteresis {
+            self.high = true;
+
+            if let Some(start) = self.cycle_start {
+                self.periods.push(self.elapsed - start);
+                self.amplitudes.push((self.max - self.min) / 2.0);
+            }
+            self.cycle_start = Some(self.elapsed);
+            self.max = speed;
+            self.min = speed;
+        }
+
+        self.output()
+    }
+
+    fn cycles(&self) -> usize {
+        self.periods.len().saturating_sub(1)
+    }
+
+    // Ultimate gain and period from the describing function of a relay with hysteresis
+    fn measure(&self) -> Option<(f32, f32)> {
+        if self.cycles() == 0 {
+            return None;
+        }
+
+        let count = self.cycles() as f32;
+        let period = self.periods[1..].iter().sum::<f32>() / count;
+        let amplitude = self.amplitudes[1..].iter().sum::<f32>() / count;
+
+        if amplitude <= self.hysteresis {
+            return None;
+        }
+
+        let ultimate_gain = 4.0 * self.amplitude
+            / (PI * (amplitude * amplitude - self.hysteresis * self.hysteresis).sqrt());
+        Some((ultimate_gain, period))
+    }
+}
+
+pub struct Tuning {
+    pub pid: Pid,
+    pub ultimate_gain: f32,
+    pub ultimate_period: f32,
+}
+
+/// Runs the relay experiment on both wheels at once.
+pub struct AutoTune {
+    pub left: RelayTuner,
+    pub right: RelayTuner,
+    pub save: bool,
+}
+
+impl AutoTune {
+    // The relays switch around the duty cycle which gives the setpoint on each wheel
+    pub fn new(setpoint: f32, bias: (f32, f32), amplitude: f32, save: bool) -> AutoTune {
+        AutoTune {
+            left: RelayTuner::new(setpoint, bias.0, amplitude),
+            right: RelayTuner::new(setpoint, bias.1, amplitude),
+            save,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        (self.left.cycles() >= CYCLES && self.right.cycles() >= CYCLES)
+            || self.left.elapsed > TIMEOUT_SECS
+    }
+
+    /// Classic Ziegler–Nichols gains from the average of both wheels.
+    /// The loop integrates and differentiates per step, so `i` and `d` are scaled by its sample time.
+    pub fn result(&self) -> Option<Tuning> {
+        let (left_gain, left_period) = self.left.measure()?;
+        let (right_gain, right_period) = self.right.measure()?;
+
+        let ultimate_gain = (left_gain + right_gain) / 2.0;
+        let ultimate_period = (left_period + right_period) / 2.0;
+        let sample_time = (self.left.elapsed + self.right.elapsed)
+            / (self.left.samples + self.right.samples) as f32;
+
+        let p = 0.6 * ultimate_gain;
+        let i = 1.2 * ultimate_gain / ultimate_period;
+        let d = 0.075 * ultimate_gain * ultimate_period;
+
+        Some(Tuning {
+            pid: Pid {
+                p,
+                i: i * sample_time,
+                d: d / sample_time,
+            },
+            ultimate_gain,
+            ultimate_period,
+        })
+    }
+}
@@ -0,0 +1,184 @@
+use std::io;
+
+use crate::motor::Side;
+use crate::persist::{data_path, load_json, save_json};
+
+const CALIBRATION_FILE: &str = "calibration.json";
+
+// Duty cycle added after every measurement of the ramp
+const DUTY_STEP: f32 = 5.0;
+// The wheel has to settle on a new duty cycle before its speed is measured
+const SETTLE_MS: isize = 300;
+const MEASURE_MS: isize = 500;
+// Slower than this counts as standing still
+const MOVING_TICKS_PER_SEC: f32 = 2.0;
+
+/// Measured encoder speed of one wheel over the duty cycles which move it.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct WheelCurve {
+    // Lowest duty cycle at which the wheel starts turning
+    pub start_duty: f32,
+    // (duty cycle, ticks per second), ascending in both
+    pub points: Vec<(f32, f32)>,
+}
+
+impl WheelCurve {
+    fn new(measurements: &[(f32, f32)]) -> Option<WheelCurve> {
+        let mut points: Vec<(f32, f32)> = Vec::new();
+        for &(duty, speed) in measurements {
+            if speed < MOVING_TICKS_PER_SEC {
+                continue;
+            }
+            // Noise mustn't make the curve go down
+            let speed = points.last().map_or(speed, |&(_, last)| speed.max(last));
+            points.push((duty, speed));
+        }
+
+        let start_duty = points.first()?.0;
+        Some(WheelCurve { start_duty, points })
+    }
+
+    // Interpolates the duty cycle for a speed, slower speeds than the wheel can do get the start duty
+    fn duty(&self, speed: f32) -> f32 {
+        if speed <= 0.0 {
+            return 0.0;
+        }
+
+        let mut lower = match self.points.first() {
+            Some(&first) => first,
+            None => return self.start_duty,
+        };
+        if speed <= lower.1 {
+            return self.start_duty;
+        }
+
+        for &upper in &self.points[1..] {
+            if speed <= upper.1 {
+                if upper.1 <= lower.1 {
+                    return upper.0;
+                }
+                return lower.0 + (upper.0 - lower.0) * (speed - lower.1) / (upper.1 - lower.1);
+            }
+            lower = upper;
+        }
+
+        lower.0
+    }
+}
+
+/// PWM curves of both motors, which differ even when they are the same model.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct Calibration {
+    pub left: Option<WheelCurve>,
+    pub right: Option<WheelCurve>,
+}
+
+impl Calibration {
+    pub fn load() -> Calibration {
+        let path = data_path(CALIBRATION_FILE);
+        match load_json(&path) {
+            Ok(calibration) => {
+                println!("Loaded motor calibration from {}", path.display());
+                calibration
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Calibration::default(),
+            Err(e) => {
+                println!(
+                    "Could not load motor calibration {} = {:?}",
+                    path.display(),
+                    e
+                );
+                Calibration::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        save_json(data_path(CALIBRATION_FILE), self)
+    }
+
+    /// Duty cycle for a wheel speed in ticks per second, `None` when the side isn't calibrated.
+    pub fn duty(&self, side: Side, speed: f32) -> Option<f32> {
+        let curve = match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        };
+        curve.as_ref().map(|curve| curve.duty(speed))
+    }
+}
+
+/// Ramps the duty cycle of one wheel after the other, the rover pivots on the wheel standing still.
+pub struct Calibrator {
+    side: Side,
+    duty: f32,
+    held_ms: isize,
+    measured_ms: isize,
+    measured_ticks: isize,
+    left: Vec<(f32, f32)>,
+    right: Vec<(f32, f32)>,
+}
+
+impl Calibrator {
+    pub fn new() -> Calibrator {
+        Calibrator {
+            side: Side::Left,
+            duty: DUTY_STEP,
+            held_ms: 0,
+            measured_ms: 0,
+            measured_ticks: 0,
+            left: Vec::new(),
+            right: Vec::new(),
+        }
+    }
+
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    pub fn duty(&self) -> f32 {
+        self.duty
+    }
+
+    /// Takes the forward ticks of the ramped wheel, returns true once both wheels are done.
+    pub fn step(&mut self, ticks: isize, duration: isize) -> bool {
+        self.held_ms += duration;
+        if self.held_ms > SETTLE_MS {
+            self.measured_ms += duration;
+            self.measured_ticks += ticks;
+        }
+
+        if self.measured_ms < MEASURE_MS {
+            return false;
+        }
+
+        let speed = self.measured_ticks.max(0) as f32 * 1000.0 / self.measured_ms as f32;
+        match self.side {
+            Side::Left => self.left.push((self.duty, speed)),
+            Side::Right => self.right.push((self.duty, speed)),
+        }
+
+        self.held_ms = 0;
+        self.measured_ms = 0;
+        self.measured_ticks = 0;
+        self.duty += DUTY_STEP;
+
+        if self.duty > 100.0 {
+            match self.side {
+                Side::Left => {
+                    self.side = Side::Right;
+                    self.duty = DUTY_STEP;
+                }
+                Side::Right => return true,
+            }
+        }
+
+        false
+    }
+
+    pub fn result(&self) -> Calibration {
+        Calibration {
+            left: WheelCurve::new(&self.left),
+            right: WheelCurve::new(&self.right),
+        }
+    }
+}
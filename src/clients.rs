@@ -0,0 +1,299 @@
+use bytes::Bytes;
+use futures::sync::mpsc;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::command::ClientCommand;
+use crate::event::{get_millis, ClientInfo, DisconnectReason, Event, TimedEvent, Transport};
+use crate::state_cache::StateCache;
+
+pub type ClientId = u32;
+
+type Tx = mpsc::UnboundedSender<Bytes>;
+type WsTx = mpsc::UnboundedSender<Message>;
+type ServerTx = mpsc::UnboundedSender<(ClientId, Bytes)>;
+type EventTx = mpsc::UnboundedSender<TimedEvent>;
+
+pub enum ClientTx {
+    Tcp(Tx),
+    Ws(WsTx),
+}
+
+struct Connection {
+    tx: ClientTx,
+    last_seen: Instant,
+    is_admin: bool,
+    info: ClientInfo,
+}
+
+pub struct Shared {
+    clients: HashMap<ClientId, Connection>,
+    next_id: ClientId,
+    server_tx: ServerTx,
+    event_tx: EventTx,
+    admin_token: Option<String>,
+    pub cache: StateCache,
+}
+
+impl Connection {
+    fn send(&mut self, json: &str) {
+        let result = match &self.tx {
+            ClientTx::Tcp(tx) => {
+                let mut line = String::with_capacity(json.len() + 2);
+                line.push_str(json);
+                line.push_str("\r\n");
+                self.info.bytes_out += line.len() as u64;
+                tx.unbounded_send(Bytes::from(line))
+                    .map_err(|e| format!("{:?}", e))
+            }
+            ClientTx::Ws(tx) => {
+                self.info.bytes_out += json.len() as u64;
+                tx.unbounded_send(Message::Text(json.to_string()))
+                    .map_err(|e| format!("{:?}", e))
+            }
+        };
+
+        match result {
+            Ok(_) => self.info.messages_out += 1,
+            Err(e) => println!("send error to client {} = {}", self.info.id, e),
+        }
+    }
+}
+
+impl Shared {
+    pub fn new(
+        server_tx: ServerTx,
+        event_tx: EventTx,
+        admin_token: Option<String>,
+        cache: StateCache,
+    ) -> Self {
+        Shared {
+            clients: HashMap::new(),
+            next_id: 1,
+            server_tx,
+            event_tx,
+            admin_token,
+            cache,
+        }
+    }
+
+    pub fn add(&mut self, addr: SocketAddr, tx: ClientTx) -> ClientId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let transport = match tx {
+            ClientTx::Tcp(_) => Transport::Tcp,
+            ClientTx::Ws(_) => Transport::Ws,
+        };
+
+        let info = ClientInfo {
+            id,
+            addr: addr.to_string(),
+            transport,
+            name: None,
+            user_agent: None,
+            connected_at: get_millis(),
+            messages_in: 0,
+            messages_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+        };
+
+        self.send_event(Event::ClientConnected {
+            client: info.clone(),
+        });
+
+        self.clients.insert(
+            id,
+            Connection {
+                tx,
+                last_seen: Instant::now(),
+                is_admin: false,
+                info,
+            },
+        );
+
+        self.send_state(id);
+
+        id
+    }
+
+    pub fn touch(&mut self, id: ClientId) {
+        if let Some(connection) = self.clients.get_mut(&id) {
+            connection.last_seen = Instant::now();
+        }
+    }
+
+    /// Forwards a line received from a client to the command handler.
+    pub fn receive(&mut self, id: ClientId, line: Bytes) {
+        if let Some(connection) = self.clients.get_mut(&id) {
+            connection.last_seen = Instant::now();
+            connection.info.messages_in += 1;
+            connection.info.bytes_in += line.len() as u64;
+        }
+
+        match self.server_tx.unbounded_send((id, line)) {
+            Ok(_) => (),
+            Err(e) => println!("send error = {:?}", e),
+        }
+    }
+
+    /// Dropping the sender ends the connection's writer, which closes the socket.
+    pub fn remove(&mut self, id: ClientId, reason: DisconnectReason) -> bool {
+        match self.clients.remove(&id) {
+            Some(connection) => {
+                self.send_event(Event::ClientDisconnected {
+                    client: connection.info,
+                    reason,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Only websocket clients are pinged, a TCP client may just listen.
+    pub fn remove_idle(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        let idle: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|(_, connection)| match connection.tx {
+                ClientTx::Ws(_) => now.duration_since(connection.last_seen) > timeout,
+                ClientTx::Tcp(_) => false,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in idle {
+            println!("Client {} timed out", id);
+            self.remove(id, DisconnectReason::Timeout);
+        }
+    }
+
+    pub fn ping_ws_clients(&self) {
+        for (id, connection) in &self.clients {
+            if let ClientTx::Ws(tx) = &connection.tx {
+                match tx.unbounded_send(Message::Ping(Vec::new())) {
+                    Ok(_) => (),
+                    Err(e) => println!("ping send error to client {} = {:?}", id, e),
+                }
+            }
+        }
+    }
+
+    pub fn broadcast(&mut self, json: &str) {
+        for (_, connection) in self.clients.iter_mut() {
+            connection.send(json);
+        }
+    }
+
+    pub fn send(&mut self, id: ClientId, event: Event) {
+        let json = serde_json::to_string(&TimedEvent::new(event)).unwrap();
+        self.send_json(id, &json);
+    }
+
+    fn send_json(&mut self, id: ClientId, json: &str) {
+        if let Some(connection) = self.clients.get_mut(&id) {
+            connection.send(json);
+        }
+    }
+
+    pub fn send_state(&mut self, id: ClientId) {
+        let state = self.cache.snapshot();
+        self.send(id, state);
+    }
+
+    /// Sends the latest event of a topic exactly as it was broadcast,
+    /// `motor` is answered with the current motor status.
+    pub fn send_topic(&mut self, id: ClientId, topic: &str) {
+        if topic == "motor" {
+            let status = self.cache.motor_status();
+            self.send(id, Event::MotorStatus { status });
+            return;
+        }
+
+        let cached = self.cache.get(topic).cloned();
+        match cached {
+            Some(json) => self.send_json(id, &json),
+            None => self.send(
+                id,
+                Event::Error {
+                    message: format!("No state for topic '{}'", topic),
+                },
+            ),
+        }
+    }
+
+    pub fn is_admin(&self, id: ClientId) -> bool {
+        self.clients
+            .get(&id)
+            .map_or(false, |connection| connection.is_admin)
+    }
+
+    pub fn handle_command(&mut self, id: ClientId, command: ClientCommand) {
+        match command {
+            ClientCommand::Hello {
+                name,
+                user_agent,
+                admin_token,
+            } => {
+                let is_admin = match (&self.admin_token, &admin_token) {
+                    (Some(expected), Some(given)) => expected == given,
+                    _ => false,
+                };
+
+                if let Some(connection) = self.clients.get_mut(&id) {
+                    connection.info.name = name;
+                    connection.info.user_agent = user_agent;
+                    connection.is_admin = is_admin;
+                }
+
+                if admin_token.is_some() && !is_admin {
+                    self.send(
+                        id,
+                        Event::Error {
+                            message: "Invalid admin token".to_string(),
+                        },
+                    );
+                }
+            }
+            ClientCommand::List => {
+                let mut clients: Vec<ClientInfo> = self
+                    .clients
+                    .values()
+                    .map(|connection| connection.info.clone())
+                    .collect();
+                clients.sort_by_key(|client| client.id);
+
+                self.send(id, Event::Clients { clients });
+            }
+            ClientCommand::Kick { id: kicked_id } => {
+                if !self.is_admin(id) {
+                    self.send(
+                        id,
+                        Event::Error {
+                            message: "Kick requires admin access".to_string(),
+                        },
+                    );
+                } else if !self.remove(kicked_id, DisconnectReason::Kicked) {
+                    self.send(
+                        id,
+                        Event::Error {
+                            message: format!("No client with id {}", kicked_id),
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    fn send_event(&self, event: Event) {
+        match self.event_tx.unbounded_send(TimedEvent::new(event)) {
+            Ok(_) => (),
+            Err(e) => println!("client event send error = {:?}", e),
+        }
+    }
+}
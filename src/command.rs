@@ -1,12 +1,14 @@
 #![allow(unused)]
 
+use crate::clients::ClientId;
+
 #[derive(Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ArduinoCommand {
     Off,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum Direction {
     Forward,
@@ -15,18 +17,208 @@ pub enum Direction {
     Left,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum MotorCommand {
+    // Gains override the active PID profile for this move only
     Move {
         speed: u8,
         direction: Direction,
         ticks: u32,
-        p: f32,
-        i: f32,
-        d: f32,
+        p: Option<f32>,
+        i: Option<f32>,
+        d: Option<f32>,
+    },
+    // Negative distance moves backward
+    MoveDistance {
+        mm: f32,
+        speed: u8,
+    },
+    // Spins in place, counter-clockwise positive
+    Rotate {
+        degrees: f32,
+        speed: u8,
     },
+    // Drives along a circle of the given radius, positive turns left (counter-clockwise),
+    // negative length drives backward
+    Arc {
+        radius_mm: f32,
+        length_mm: f32,
+        speed: u8,
+    },
+    // Target velocities in m/s and rad/s (counter-clockwise positive)
+    Drive {
+        linear: f32,
+        angular: f32,
+    },
+    // Drives to a position in mm of the odometry frame, then turns to `theta` (radians) if given
+    GoTo {
+        x: f32,
+        y: f32,
+        theta: Option<f32>,
+        speed: u8,
+    },
+    // Signed wheel speeds in percent of full speed, runs like `Drive`
+    Wheels {
+        left: f32,
+        right: f32,
+    },
+    // Turns each wheel by a signed number of ticks, both arriving at the same time,
+    // `speed` is the speed of the wheel with more ticks
+    WheelTicks {
+        left: isize,
+        right: isize,
+        speed: u8,
+    },
+    // Lets the wheels roll out
     Stop,
+    // Shorts the motors for a short, repeatable stopping distance
+    Brake,
+    // Runs after the motions queued before it and a running move, takes over from a velocity drive.
+    // Only moves with a distance can be queued, a goto can't be held by a pause
+    Enqueue {
+        command: Box<MotorCommand>,
+    },
+    ClearQueue,
+    // Decelerates and holds the current queued move
+    Pause,
+    Resume,
+    // Aborts the current queued move and starts the next one
+    Cancel,
+    // Drives forward with relay feedback around `speed` to find PID gains,
+    // the duty cycle swings by `amplitude` around the feed forward
+    AutoTune {
+        speed: u8,
+        amplitude: u8,
+        #[serde(default)]
+        save: bool,
+    },
+    // Ramps each motor on its own to map duty cycles to speeds,
+    // the rover pivots around the other wheel
+    Calibrate,
+    // Changes the gains of the running motion, `save` also stores them in the active profile
+    SetGains {
+        p: Option<f32>,
+        i: Option<f32>,
+        d: Option<f32>,
+        #[serde(default)]
+        save: bool,
+    },
+    // Stores the gains in use as a named profile
+    SaveProfile {
+        name: String,
+    },
+    SelectProfile {
+        name: String,
+    },
+    DeleteProfile {
+        name: String,
+    },
+    // Allows moving again after a stall or encoder fault
+    ClearFault,
+    // Sends a `motorrunstat` event for every control step while moving
+    Telemetry {
+        enabled: bool,
+    },
+    // PWM frequency of the motors in Hz (40-1000) while standing still,
+    // `correction` scales the prescale when the oscillator is off
+    PwmFrequency {
+        frequency: u16,
+        correction: Option<f32>,
+    },
+    // Records the motions from now on, with the distances the wheels turned, as a named trajectory
+    Record {
+        name: String,
+    },
+    // Saves the trajectory being recorded
+    StopRecording,
+    // Queues the recorded motions as `wheelticks` moves at their recorded speeds unless `speed` is given,
+    // `reverse` drives the trajectory backward to where it started
+    Replay {
+        name: String,
+        #[serde(default)]
+        reverse: bool,
+        speed: Option<u8>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OdometryCommand {
+    // Makes the current position the origin, facing along x
+    ResetPose,
+    // Position in mm, heading in radians counter-clockwise from x
+    SetPose { x: f32, y: f32, theta: f32 },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServoCommand {
+    // Moves to the angle in degrees at once
+    Set {
+        name: String,
+        angle: f32,
+    },
+    // Moves to the angle at `speed` degrees per second, limited by the configured maximum.
+    // A servo which hasn't been driven yet jumps to the angle.
+    Sweep {
+        name: String,
+        angle: f32,
+        speed: Option<f32>,
+    },
+    // Holds the angle reached by a sweep
+    Stop {
+        name: String,
+    },
+    // Stops the pulses so the servo doesn't hold its position
+    Off {
+        name: String,
+    },
+}
+
+// Bench diagnostics for admins, they bypass the handlers which normally own the hardware
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DebugCommand {
+    // Raw duty cycle (0-4095) on a channel of a PWM board, read back after writing
+    Pwm {
+        board: String,
+        channel: u8,
+        duty_cycle: u16,
+    },
+    GpioSet {
+        pin: u64,
+        value: u8,
+    },
+    GpioGet {
+        pin: u64,
+    },
+    // `bus` defaults to /dev/i2c-1
+    I2cRead {
+        bus: Option<String>,
+        address: u16,
+        register: u8,
+    },
+    I2cWrite {
+        bus: Option<String>,
+        address: u16,
+        register: u8,
+        value: u8,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientCommand {
+    Hello {
+        name: Option<String>,
+        user_agent: Option<String>,
+        admin_token: Option<String>,
+    },
+    List,
+    Kick {
+        id: ClientId,
+    },
 }
 
 #[derive(Deserialize)]
@@ -34,4 +226,10 @@ pub enum MotorCommand {
 pub enum Command {
     Motor { command: MotorCommand },
     Arduino { command: ArduinoCommand },
+    Odometry { command: OdometryCommand },
+    Servo { command: ServoCommand },
+    Debug { command: DebugCommand },
+    Client { command: ClientCommand },
+    GetState,
+    Get { topic: String },
 }
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+use crate::fault::FaultLimits;
+use crate::geometry::Geometry;
+use crate::odometry::OdometryConfig;
+use crate::persist::{data_path, load_json};
+use crate::profile::MotionLimits;
+use crate::pwm::PwmConfig;
+use crate::servo::ServoConfig;
+
+const CONFIG_FILE: &str = "rover.json";
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub geometry: Geometry,
+    pub motion: MotionLimits,
+    pub faults: FaultLimits,
+    pub odometry: OdometryConfig,
+    pub pwm: PwmConfig,
+    // By name, on PWM channels other than the motors'
+    pub servos: BTreeMap<String, ServoConfig>,
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let path = env::var("ROVER_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| data_path(CONFIG_FILE));
+
+        match load_json(&path) {
+            Ok(config) => {
+                println!("Loaded config from {}", path.display());
+                config
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                println!("No config at {}, using defaults", path.display());
+                Config::default()
+            }
+            Err(e) => {
+                println!(
+                    "Could not load config {} = {:?}, using defaults",
+                    path.display(),
+                    e
+                );
+                Config::default()
+            }
+        }
+    }
+}
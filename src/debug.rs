@@ -0,0 +1,124 @@
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::LinuxI2CDevice;
+use sysfs_gpio::{Direction, Pin};
+
+use crate::command::DebugCommand;
+use crate::event::DebugResult;
+use crate::pca9685::{CHANNELS, STEPS};
+use crate::pwm::{SharedPwm, DEFAULT_I2C_BUS};
+
+fn pwm(
+    pwm: &SharedPwm,
+    board: String,
+    channel: u8,
+    duty_cycle: u16,
+) -> Result<DebugResult, String> {
+    if channel >= CHANNELS || duty_cycle >= STEPS {
+        return Err(format!(
+            "PWM channels go up to {} and duty cycles up to {}",
+            CHANNELS - 1,
+            STEPS - 1
+        ));
+    }
+
+    let output = pwm
+        .lock()
+        .unwrap()
+        .set_raw(&board, channel, duty_cycle)
+        .map_err(|e| {
+            format!(
+                "Could not set PWM channel {} on '{}': {}",
+                channel, board, e
+            )
+        })?;
+    Ok(DebugResult::Pwm {
+        board,
+        channel,
+        output,
+    })
+}
+
+// Exporting a pin the server already uses, e.g. the motor's, leaves it as it is
+fn export(number: u64) -> Result<Pin, String> {
+    let pin = Pin::new(number);
+    pin.export()
+        .map_err(|e| format!("Could not export GPIO {}: {}", number, e))?;
+    Ok(pin)
+}
+
+// Switches to an output and sets the value in one write, so the pin doesn't glitch
+fn gpio_set(number: u64, value: u8) -> Result<DebugResult, String> {
+    let pin = export(number)?;
+
+    let direction = if value == 0 {
+        Direction::Low
+    } else {
+        Direction::High
+    };
+    pin.set_direction(direction)
+        .and_then(|_| pin.get_value())
+        .map(|value| DebugResult::Gpio { pin: number, value })
+        .map_err(|e| format!("Could not set GPIO {}: {}", number, e))
+}
+
+// Reading leaves the direction alone, outputs read back what they are driving
+fn gpio_get(number: u64) -> Result<DebugResult, String> {
+    let pin = export(number)?;
+
+    pin.get_value()
+        .map(|value| DebugResult::Gpio { pin: number, value })
+        .map_err(|e| format!("Could not read GPIO {}: {}", number, e))
+}
+
+fn i2c(
+    bus: Option<String>,
+    address: u16,
+    register: u8,
+    value: Option<u8>,
+) -> Result<DebugResult, String> {
+    let bus = bus.unwrap_or_else(|| DEFAULT_I2C_BUS.to_string());
+    let mut device = LinuxI2CDevice::new(&bus, address)
+        .map_err(|e| format!("Could not open I2C {} at {:#04x}: {}", bus, address, e))?;
+
+    let result = match value {
+        Some(value) => device.smbus_write_byte_data(register, value).map(|_| value),
+        None => device.smbus_read_byte_data(register),
+    };
+    let value = result.map_err(|e| {
+        format!(
+            "Could not access register {:#04x} at {:#04x}: {}",
+            register, address, e
+        )
+    })?;
+
+    Ok(DebugResult::I2c {
+        bus,
+        address,
+        register,
+        value,
+    })
+}
+
+/// Runs a debug command directly against the hardware.
+pub fn run(shared_pwm: &SharedPwm, command: DebugCommand) -> Result<DebugResult, String> {
+    match command {
+        DebugCommand::Pwm {
+            board,
+            channel,
+            duty_cycle,
+        } => pwm(shared_pwm, board, channel, duty_cycle),
+        DebugCommand::GpioSet { pin, value } => gpio_set(pin, value),
+        DebugCommand::GpioGet { pin } => gpio_get(pin),
+        DebugCommand::I2cRead {
+            bus,
+            address,
+            register,
+        } => i2c(bus, address, register, None),
+        DebugCommand::I2cWrite {
+            bus,
+            address,
+            register,
+            value,
+        } => i2c(bus, address, register, Some(value)),
+    }
+}
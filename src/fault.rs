@@ -0,0 +1,115 @@
+use crate::event::{FaultCause, MotorFault, MotorRunStat, Wheel};
+use crate::geometry::Geometry;
+
+/// Thresholds for detecting a jammed wheel or a broken encoder.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct FaultLimits {
+    // Duty cycle above which a wheel has to be turning
+    pub stall_output: f32,
+    pub stall_ms: u32,
+    // How far one wheel may fall behind the other
+    pub max_disagreement_mm: f32,
+}
+
+impl Default for FaultLimits {
+    fn default() -> FaultLimits {
+        FaultLimits {
+            stall_output: 30.0,
+            stall_ms: 500,
+            max_disagreement_mm: 60.0,
+        }
+    }
+}
+
+/// Watches the control steps of a motion for wheels which don't follow their duty cycle.
+pub struct FaultDetector {
+    stall_output: f32,
+    stall_ms: isize,
+    max_disagreement: f32,
+    left_stalled_ms: isize,
+    right_stalled_ms: isize,
+}
+
+impl FaultDetector {
+    pub fn new(limits: &FaultLimits, geometry: &Geometry) -> FaultDetector {
+        FaultDetector {
+            stall_output: limits.stall_output,
+            stall_ms: limits.stall_ms as isize,
+            max_disagreement: limits.max_disagreement_mm / geometry.mm_per_tick(),
+            left_stalled_ms: 0,
+            right_stalled_ms: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.left_stalled_ms = 0;
+        self.right_stalled_ms = 0;
+    }
+
+    /// `coupled` tells whether the coupling error measures the wheels against a fixed ratio,
+    /// the disagreement check is skipped otherwise.
+    pub fn check(&mut self, stat: &MotorRunStat, coupled: bool) -> Option<MotorFault> {
+        if let Some(fault) = self.check_stall(
+            stat.left.output,
+            stat.left.ticks,
+            stat.right.output,
+            stat.right.ticks,
+            stat.duration,
+        ) {
+            return Some(fault);
+        }
+
+        // The coupling error is positive when the left wheel is ahead
+        if !coupled {
+            None
+        } else if stat.coupling_error > self.max_disagreement {
+            Some(MotorFault {
+                wheel: Wheel::Right,
+                cause: FaultCause::Disagreement,
+            })
+        } else if stat.coupling_error < -self.max_disagreement {
+            Some(MotorFault {
+                wheel: Wheel::Left,
+                cause: FaultCause::Disagreement,
+            })
+        } else {
+            None
+        }
+    }
+
+    // A wheel is stalled when it is driven hard enough but its encoder doesn't count
+    pub fn check_stall(
+        &mut self,
+        left_output: f32,
+        left_ticks: isize,
+        right_output: f32,
+        right_ticks: isize,
+        duration: isize,
+    ) -> Option<MotorFault> {
+        self.left_stalled_ms = if left_output >= self.stall_output && left_ticks == 0 {
+            self.left_stalled_ms + duration
+        } else {
+            0
+        };
+        self.right_stalled_ms = if right_output >= self.stall_output && right_ticks == 0 {
+            self.right_stalled_ms + duration
+        } else {
+            0
+        };
+
+        if self.left_stalled_ms >= self.stall_ms {
+            Some(MotorFault {
+                wheel: Wheel::Left,
+                cause: FaultCause::Stall,
+            })
+        } else if self.right_stalled_ms >= self.stall_ms {
+            Some(MotorFault {
+                wheel: Wheel::Right,
+                cause: FaultCause::Stall,
+            })
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::persist::{data_path, load_json, save_json};
+
+const PROFILES_FILE: &str = "pid.json";
+const DEFAULT_PROFILE: &str = "default";
+
+/// Speed loop gains, per control step of the encoder snapshots.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+pub struct Pid {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+}
+
+// Used until tuned gains have been saved
+pub const DEFAULT_PID: Pid = Pid {
+    p: 0.2,
+    i: 0.05,
+    d: 0.0,
+};
+
+/// Named gains for different floors, e.g. "carpet" or "tile".
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PidProfiles {
+    pub active: String,
+    pub profiles: BTreeMap<String, Pid>,
+}
+
+impl Default for PidProfiles {
+    fn default() -> PidProfiles {
+        let mut profiles = BTreeMap::new();
+        profiles.insert(DEFAULT_PROFILE.to_string(), DEFAULT_PID);
+
+        PidProfiles {
+            active: DEFAULT_PROFILE.to_string(),
+            profiles,
+        }
+    }
+}
+
+impl PidProfiles {
+    pub fn load() -> PidProfiles {
+        let path = data_path(PROFILES_FILE);
+        match load_json(&path) {
+            Ok(profiles) => {
+                println!("Loaded PID profiles from {}", path.display());
+                profiles
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => PidProfiles::default(),
+            Err(e) => {
+                println!("Could not load PID profiles {} = {:?}", path.display(), e);
+                PidProfiles::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        save_json(data_path(PROFILES_FILE), self)
+    }
+
+    // Falls back to the defaults when the active profile has been removed from the file
+    pub fn active(&self) -> Pid {
+        self.profiles
+            .get(&self.active)
+            .cloned()
+            .unwrap_or(DEFAULT_PID)
+    }
+
+    pub fn select(&mut self, name: &str) -> bool {
+        if self.profiles.contains_key(name) {
+            self.active = name.to_string();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set(&mut self, name: &str, pid: Pid) {
+        self.profiles.insert(name.to_string(), pid);
+    }
+
+    // The active profile can't be removed
+    pub fn remove(&mut self, name: &str) -> bool {
+        name != self.active && self.profiles.remove(name).is_some()
+    }
+}
@@ -0,0 +1,57 @@
+use std::f32::consts::PI;
+
+/// Wheel and chassis dimensions used to convert between encoder ticks and distances.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Geometry {
+    pub ticks_per_revolution: f32,
+    pub wheel_diameter_mm: f32,
+    // Distance between the centres of the wheels
+    pub track_width_mm: f32,
+}
+
+impl Default for Geometry {
+    fn default() -> Geometry {
+        Geometry {
+            ticks_per_revolution: 80.0,
+            wheel_diameter_mm: 65.0,
+            track_width_mm: 140.0,
+        }
+    }
+}
+
+impl Geometry {
+    pub fn mm_per_tick(&self) -> f32 {
+        PI * self.wheel_diameter_mm / self.ticks_per_revolution
+    }
+
+    pub fn ticks_for_distance(&self, mm: f32) -> u32 {
+        (mm.abs() / self.mm_per_tick()).round() as u32
+    }
+
+    // Each wheel travels along a circle with the track width as its diameter
+    pub fn ticks_for_rotation(&self, degrees: f32) -> u32 {
+        let arc_mm = PI * self.track_width_mm * degrees.abs() / 360.0;
+        self.ticks_for_distance(arc_mm)
+    }
+
+    /// Signed wheel speed ratios of an arc, the outer wheel runs at 1,
+    /// and the distance of the outer wheel in ticks.
+    /// Positive radius turns left (counter-clockwise), negative length drives backward.
+    pub fn arc(&self, radius_mm: f32, length_mm: f32) -> Option<((f32, f32), u32)> {
+        if radius_mm == 0.0 {
+            return None;
+        }
+
+        // Each wheel covers the arc on its own radius
+        let left = (radius_mm - self.track_width_mm / 2.0) / radius_mm;
+        let right = (radius_mm + self.track_width_mm / 2.0) / radius_mm;
+        let outer = left.abs().max(right.abs());
+        let sign = if length_mm < 0.0 { -1.0 } else { 1.0 };
+
+        Some((
+            (sign * left / outer, sign * right / outer),
+            self.ticks_for_distance(length_mm * outer),
+        ))
+    }
+}
@@ -10,12 +10,14 @@ use std::io::{Error, ErrorKind};
 extern crate serde_derive;
 
 mod pca9685;
+mod pwm;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::sync::mpsc;
 use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
+use tokio::timer::Interval;
 
 use tokio_tungstenite::accept_async;
 use tokio_tungstenite::tungstenite::Message;
@@ -23,40 +25,61 @@ use tokio_tungstenite::tungstenite::Message;
 use std::process;
 use std::str;
 
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::env;
 use std::sync::{Arc, Mutex};
-type Tx = mpsc::UnboundedSender<Bytes>;
+use std::thread;
+use std::time::{Duration, Instant};
 type Rx = mpsc::UnboundedReceiver<Bytes>;
 
 mod sensors;
-use crate::sensors::event::{ArduinoEvent, Event, TimedEvent};
+use crate::sensors::event::{ArduinoEvent, DisconnectReason, Event, TimedEvent};
 use crate::sensors::*;
 mod command;
 use crate::command::Command;
+mod config;
+use crate::config::Config;
+mod autotune;
+mod calibration;
+mod clients;
+mod debug;
+mod fault;
+mod gains;
+mod geometry;
+mod persist;
+mod profile;
+use crate::clients::{ClientId, ClientTx, Shared};
+mod state_cache;
+use crate::state_cache::StateCache;
 
 mod motor;
 mod motor_handler;
+mod navigation;
 use crate::motor_handler::MotorHandler;
+mod odometry;
+use crate::odometry::Odometry;
+use crate::pwm::Pwm;
+mod servo;
+use crate::servo::ServoHandler;
+mod trajectory;
 
 type EventTx = mpsc::UnboundedSender<TimedEvent>;
 type EventRx = mpsc::UnboundedReceiver<TimedEvent>;
 type CommandTx = mpsc::UnboundedSender<Command>;
 type CommandRx = mpsc::UnboundedReceiver<Command>;
 
-type WsTx = mpsc::UnboundedSender<Message>;
+type ServerTx = mpsc::UnboundedSender<(ClientId, Bytes)>;
+type ServerRx = mpsc::UnboundedReceiver<(ClientId, Bytes)>;
 
-struct Shared {
-    clients: HashMap<SocketAddr, Tx>,
-    ws_clients: HashMap<SocketAddr, WsTx>,
-    server_tx: Tx,
-}
+const TCP_KEEPALIVE_SECS: u64 = 10;
+const WS_PING_INTERVAL_MS: u64 = 5000;
+// Websocket clients which don't answer the pings, TCP clients are left to the keepalive
+const CLIENT_IDLE_TIMEOUT_MS: u64 = 30000;
 
 struct Client {
     lines: Lines,
     state: Arc<Mutex<Shared>>,
     rx: Rx,
-    addr: SocketAddr,
+    id: ClientId,
 }
 
 #[derive(Debug)]
@@ -66,27 +89,17 @@ struct Lines {
     wr: BytesMut,
 }
 
-impl Shared {
-    fn new(server_tx: Tx) -> Self {
-        Shared {
-            clients: HashMap::new(),
-            ws_clients: HashMap::new(),
-            server_tx,
-        }
-    }
-}
-
 impl Client {
     fn new(state: Arc<Mutex<Shared>>, lines: Lines) -> Client {
         let addr = lines.socket.peer_addr().unwrap();
         let (tx, rx) = mpsc::unbounded();
-        state.lock().unwrap().clients.insert(addr, tx);
+        let id = state.lock().unwrap().add(addr, ClientTx::Tcp(tx));
 
         Client {
             lines,
             state,
             rx,
-            addr,
+            id,
         }
     }
 }
@@ -107,7 +120,9 @@ impl Future for Client {
                         task::current().notify();
                     }
                 }
-                _ => break,
+                // The sender is only dropped when the client is removed from `Shared`
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break,
             }
         }
 
@@ -115,21 +130,15 @@ impl Future for Client {
 
         while let Async::Ready(line) = self.lines.poll()? {
             if let Some(message) = line {
-                let mut line = BytesMut::new();
-                line.extend_from_slice(&message);
-
-                let line = line.freeze();
-
-                match &self
-                    .state
-                    .lock()
-                    .unwrap()
-                    .server_tx
-                    .unbounded_send(line.clone())
-                {
-                    Ok(_) => (),
-                    Err(e) => println!("send error = {:?}", e),
+                let mut state = self.state.lock().unwrap();
+
+                // Empty lines only keep the connection alive
+                if message.is_empty() {
+                    state.touch(self.id);
+                    continue;
                 }
+
+                state.receive(self.id, message.freeze());
             } else {
                 return Ok(Async::Ready(()));
             }
@@ -140,7 +149,10 @@ impl Future for Client {
 
 impl Drop for Client {
     fn drop(&mut self) {
-        self.state.lock().unwrap().clients.remove(&self.addr);
+        self.state
+            .lock()
+            .unwrap()
+            .remove(self.id, DisconnectReason::Closed);
     }
 }
 
@@ -210,7 +222,15 @@ impl Stream for Lines {
     }
 }
 
+fn set_keepalive(socket: &TcpStream) {
+    match socket.set_keepalive(Some(Duration::from_secs(TCP_KEEPALIVE_SECS))) {
+        Ok(_) => (),
+        Err(e) => println!("could not set keepalive = {:?}", e),
+    }
+}
+
 fn process(socket: TcpStream, state: Arc<Mutex<Shared>>) {
+    set_keepalive(&socket);
     let lines = Lines::new(socket);
 
     let peer = Client::new(state, lines).map_err(|e| {
@@ -227,32 +247,31 @@ fn process_ws(
     let addr = socket
         .peer_addr()
         .expect("connected streams should have a peer address");
+    set_keepalive(&socket);
     let state_clone = state.clone();
     let future = accept_async(socket)
         .and_then(move |ws_stream| {
             println!("New WebSocket connection: {}", addr);
 
             let (tx, rx) = futures::sync::mpsc::unbounded();
-            state.lock().unwrap().ws_clients.insert(addr, tx);
+            let id = state.lock().unwrap().add(addr, ClientTx::Ws(tx));
             let (sink, source) = ws_stream.split();
 
             let ws_reader = source.for_each(move |message| {
-                println!("Received a ws message: {}", message);
+                let mut state = state_clone.lock().unwrap();
 
-                let mut line = BytesMut::new();
-                line.extend_from_slice(&message.into_data());
-                let line = line.freeze();
-
-                match state_clone
-                    .lock()
-                    .unwrap()
-                    .server_tx
-                    .unbounded_send(line.clone())
-                {
-                    Ok(_) => (),
-                    Err(e) => println!("send error = {:?}", e),
+                match message {
+                    Message::Ping(_) | Message::Pong(_) => {
+                        state.touch(id);
+                        return Ok(());
+                    }
+                    _ => (),
                 }
 
+                println!("Received a ws message: {}", message);
+
+                state.receive(id, Bytes::from(message.into_data()));
+
                 Ok(())
             });
 
@@ -268,9 +287,7 @@ fn process_ws(
                 .select(ws_writer.map(|_| ()).map_err(|_| ()));
 
             tokio::spawn(connection.then(move |_| {
-                // remove socket from state here
-                //  connections_inner.lock().unwrap().remove(&addr);
-                state.lock().unwrap().ws_clients.remove(&addr);
+                state.lock().unwrap().remove(id, DisconnectReason::Closed);
                 println!("Websocket connection closed: {}", addr);
                 Ok(())
             }));
@@ -295,10 +312,32 @@ pub fn main() {
         Err(_) => println!("Could not read from i2cdetect"),
     };
 
-    let (server_tx, server_rx): (Tx, Rx) = mpsc::unbounded();
+    let config = Config::load();
+
+    let (server_tx, server_rx): (ServerTx, ServerRx) = mpsc::unbounded();
     let (sensors_tx, sensors_rx): (EventTx, EventRx) = mpsc::unbounded();
     let (_commands_tx, _commands_rx): (CommandTx, CommandRx) = mpsc::unbounded();
-    let state = Arc::new(Mutex::new(Shared::new(server_tx)));
+    let sensors_tx_arc = Arc::new(Mutex::new(sensors_tx.clone()));
+
+    let (odometry, odometry_tx_command, odometry_tx_event) =
+        Odometry::new(sensors_tx_arc.clone(), &config);
+    let pwm = Pwm::open(&config.pwm).shared();
+    let (motor_handler, motor_handler_tx_command, motor_handler_tx_event) = MotorHandler::new(
+        sensors_tx_arc.clone(),
+        &config,
+        odometry.pose_handle(),
+        pwm.clone(),
+    );
+    let debug_pwm = pwm.clone();
+    let (servo_handler, servo_tx_command) = ServoHandler::new(sensors_tx_arc.clone(), &config, pwm);
+
+    let admin_token = env::var("ROVER_ADMIN_TOKEN").ok();
+    let state = Arc::new(Mutex::new(Shared::new(
+        server_tx,
+        sensors_tx,
+        admin_token,
+        StateCache::new(motor_handler.status_handle()),
+    )));
 
     let addr = "0.0.0.0:5000".parse().unwrap();
     let ws_addr = "0.0.0.0:5001".parse().unwrap();
@@ -327,13 +366,21 @@ pub fn main() {
 
     println!("server running on localhost:5000");
 
-    let sensors_tx_arc = Arc::new(Mutex::new(sensors_tx));
+    let local_state = state.clone();
+    let keepalive = Interval::new(Instant::now(), Duration::from_millis(WS_PING_INTERVAL_MS))
+        .for_each(move |_| {
+            let mut shared = local_state.lock().unwrap();
+            shared.remove_idle(Duration::from_millis(CLIENT_IDLE_TIMEOUT_MS));
+            shared.ping_ws_clients();
+
+            Ok(())
+        })
+        .map_err(|e| print!("interval errored; err={:?}", e));
 
-    let (motor_handler, motor_handler_tx_command, motor_handler_tx_event) =
-        MotorHandler::new(sensors_tx_arc.clone());
     let (arduino, arduino_tx) = arduino::Arduino::new(sensors_tx_arc.clone());
+    let local_state = state.clone();
     let receive_messages = server_rx
-        .for_each(move |line| {
+        .for_each(move |(client_id, line)| {
             println!("Received line on server: {:?}", line);
 
             let command_result: Result<Command, serde_json::Error> = serde_json::from_slice(&line);
@@ -345,6 +392,44 @@ pub fn main() {
                 Ok(Command::Motor { command }) => {
                     motor_handler_tx_command.unbounded_send(command).unwrap();
                 }
+                Ok(Command::Odometry { command }) => {
+                    odometry_tx_command.unbounded_send(command).unwrap();
+                }
+                Ok(Command::Servo { command }) => {
+                    servo_tx_command.unbounded_send(command).unwrap();
+                }
+                Ok(Command::Debug { command }) => {
+                    let is_admin = local_state.lock().unwrap().is_admin(client_id);
+                    if !is_admin {
+                        let event = Event::Error {
+                            message: "Debug commands require admin access".to_string(),
+                        };
+                        local_state.lock().unwrap().send(client_id, event);
+                    } else {
+                        // The hardware is slow, other clients shouldn't wait for it
+                        let pwm = debug_pwm.clone();
+                        let state = local_state.clone();
+                        thread::spawn(move || {
+                            let event = match debug::run(&pwm, command) {
+                                Ok(result) => Event::Debug { result },
+                                Err(message) => Event::Error { message },
+                            };
+                            state.lock().unwrap().send(client_id, event);
+                        });
+                    }
+                }
+                Ok(Command::Client { command }) => {
+                    local_state
+                        .lock()
+                        .unwrap()
+                        .handle_command(client_id, command);
+                }
+                Ok(Command::GetState) => {
+                    local_state.lock().unwrap().send_state(client_id);
+                }
+                Ok(Command::Get { topic }) => {
+                    local_state.lock().unwrap().send_topic(client_id, &topic);
+                }
                 Err(e) => println!("could not deserialize command = {:?}", e),
             };
 
@@ -357,6 +442,7 @@ pub fn main() {
     let local_state = state.clone();
     let receive_sensor_messages = sensors_rx
         .for_each(move |event| {
+            let topic = event.event.topic();
             let event_json = serde_json::to_string(&event).unwrap();
             //            println!("Received sensor message, broadcasting: {:?}", &event_json);
 
@@ -366,6 +452,7 @@ pub fn main() {
                     time: _u128,
                 } => match e {
                     ArduinoEvent::Encoders { encoders } => {
+                        odometry_tx_event.unbounded_send(encoders.clone()).unwrap();
                         motor_handler_tx_event.unbounded_send(encoders).unwrap();
                         ()
                     }
@@ -374,23 +461,11 @@ pub fn main() {
                 _ => (),
             };
 
-            let mut line = BytesMut::new();
-            line.extend_from_slice(event_json.as_bytes());
-            line.extend_from_slice(b"\r\n");
-            let line = line.freeze();
-
-            let shared = local_state.lock().unwrap();
-            for (_, tx) in &shared.clients {
-                tx.unbounded_send(line.clone()).unwrap();
-            }
-
-            for (_, ws_tx) in &shared.ws_clients {
-                ws_tx
-                    .unbounded_send(Message::Text(
-                        str::from_utf8(&line).unwrap().trim().to_string(),
-                    ))
-                    .unwrap();
+            let mut shared = local_state.lock().unwrap();
+            if let Some(topic) = topic {
+                shared.cache.update(topic, event_json.clone());
             }
+            shared.broadcast(&event_json);
 
             Ok(())
         })
@@ -408,6 +483,7 @@ pub fn main() {
 
     let joined = server
         .join(ws_server)
+        .join(keepalive)
         .join(receive_messages)
         .join(receive_sensor_messages)
         .join(ir.run())
@@ -417,6 +493,8 @@ pub fn main() {
         .join(axl.run())
         .join(arduino.run())
         .join(motor_handler.run())
+        .join(odometry.run())
+        .join(servo_handler.run())
         .map(|_| ());
 
     tokio::run(joined);
@@ -1,24 +1,34 @@
-use crate::pca9685::PCA9685;
-use i2cdev::linux::*;
+use crate::pwm::{PwmError, SharedPwm, LEFT_MOTOR, RIGHT_MOTOR};
+use std::time::{Duration, Instant};
 use sysfs_gpio::{Direction, Pin};
 
-const DEFAULT_PCA9685_ADDRESS: u16 = 0x40;
+// PWM channels of the motors, left and right
+pub const MOTOR_CHANNELS: [&str; 2] = [LEFT_MOTOR, RIGHT_MOTOR];
+const FULL_DUTY_CYCLE: u16 = 4095;
+// The bridge is switched off this long before it changes direction
+const DEAD_TIME_MS: u64 = 20;
 
 pub struct Motor {
-    pca: PCA9685,
+    pwm: SharedPwm,
     in1_pin: Pin,
     in2_pin: Pin,
     in3_pin: Pin,
     in4_pin: Pin,
+    // Per side, `None` while braking or before the first direction is set
+    directions: [Option<Dir>; 2],
+    // Per side, the direction waiting for the dead time to pass and since when it has been waiting
+    switching: [Option<(Dir, Instant)>; 2],
+    duty_cycles: [u16; 2],
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Side {
     Left,
     Right,
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum Dir {
     Forward,
     Backward,
@@ -29,12 +39,15 @@ fn prepare_pin(pin: &Pin) {
     pin.set_direction(Direction::Low).unwrap();
 }
 
-impl Motor {
-    pub fn new() -> Result<Motor, LinuxI2CError> {
-        let i2cdevice = LinuxI2CDevice::new("/dev/i2c-1", DEFAULT_PCA9685_ADDRESS)?;
-
-        let pca = PCA9685::new(i2cdevice, 60)?;
+fn side_index(side: Side) -> usize {
+    match side {
+        Side::Left => 0,
+        Side::Right => 1,
+    }
+}
 
+impl Motor {
+    pub fn new(pwm: SharedPwm) -> Motor {
         let in1_pin = Pin::new(6);
         prepare_pin(&in1_pin);
 
@@ -47,60 +60,125 @@ impl Motor {
         let in4_pin = Pin::new(17);
         prepare_pin(&in4_pin);
 
-        Ok(Motor {
-            pca,
+        Motor {
+            pwm,
             in1_pin,
             in2_pin,
             in3_pin,
             in4_pin,
-        })
+            directions: [None, None],
+            switching: [None, None],
+            duty_cycles: [0, 0],
+        }
     }
 
-    pub fn set_direction(&mut self, side: Side, direction: Dir) {
+    fn set_pins(&mut self, side: Side, in_a: u8, in_b: u8) {
         match side {
-            Side::Left => match direction {
-                Dir::Forward => {
-                    self.in1_pin.set_value(1).unwrap();
-                    self.in2_pin.set_value(0).unwrap();
-                }
-                Dir::Backward => {
-                    self.in1_pin.set_value(0).unwrap();
-                    self.in2_pin.set_value(1).unwrap();
-                }
-            },
-            Side::Right => match direction {
-                Dir::Forward => {
-                    self.in3_pin.set_value(1).unwrap();
-                    self.in4_pin.set_value(0).unwrap();
-                }
-                Dir::Backward => {
-                    self.in3_pin.set_value(0).unwrap();
-                    self.in4_pin.set_value(1).unwrap();
-                }
-            },
+            Side::Left => {
+                self.in1_pin.set_value(in_a).unwrap();
+                self.in2_pin.set_value(in_b).unwrap();
+            }
+            Side::Right => {
+                self.in3_pin.set_value(in_a).unwrap();
+                self.in4_pin.set_value(in_b).unwrap();
+            }
+        }
+    }
+
+    fn set_duty_cycle(&mut self, side: Side, on: u16) {
+        let channel = MOTOR_CHANNELS[side_index(side)];
+        self.pwm
+            .lock()
+            .unwrap()
+            .set_duty_cycle(channel, on)
+            .unwrap();
+        self.duty_cycles[side_index(side)] = on;
+    }
+
+    fn set_direction_pins(&mut self, side: Side, direction: Dir) {
+        match direction {
+            Dir::Forward => self.set_pins(side, 1, 0),
+            Dir::Backward => self.set_pins(side, 0, 1),
+        }
+        self.directions[side_index(side)] = Some(direction);
+        self.switching[side_index(side)] = None;
+    }
+
+    /// Changing direction while driving or braking goes through zero speed first:
+    /// the wheel coasts and `set_speed` switches the direction once the dead time has passed.
+    pub fn set_direction(&mut self, side: Side, direction: Dir) {
+        let index = side_index(side);
+        if self.directions[index] == Some(direction) {
+            // A switch back before the dead time passed, the pins never changed
+            self.switching[index] = None;
+            return;
+        }
+
+        if let Some((_, since)) = self.switching[index] {
+            self.switching[index] = Some((direction, since));
+        } else if self.duty_cycles[index] > 0 {
+            self.set_duty_cycle(side, 0);
+            self.switching[index] = Some((direction, Instant::now()));
+        } else {
+            self.set_direction_pins(side, direction);
         }
     }
 
     pub fn set_speed(&mut self, side: Side, speed: f32) -> () {
         println!("Setting speed to {} on side {:?}", speed, side);
 
-        // const scaled = speed/100 * 82 + 18
-        let duty_cycle = 4095f32;
-        let on = (duty_cycle * speed / 100f32).round();
+        // Keeps coasting until the direction can be switched
+        if let Some((direction, since)) = self.switching[side_index(side)] {
+            if since.elapsed() < Duration::from_millis(DEAD_TIME_MS) {
+                return;
+            }
+            self.set_direction_pins(side, direction);
+        }
 
-        let pwm_pin = match side {
-            Side::Left => 0,
-            Side::Right => 1,
-        };
+        // The dead zone is handled by the calibrated feed forward of `MotorHandler`
+        let duty_cycle = FULL_DUTY_CYCLE as f32;
+        let on = (duty_cycle * speed / 100f32).round();
 
         println!("Setting pwm to {}", on);
-        self.pca.set_duty_cycle(pwm_pin, on as u16).unwrap();
+        self.set_duty_cycle(side, on as u16);
         ()
     }
 
+    /// Changes the PWM frequency of the motors' boards, everything else on them changes too,
+    /// returns the prescale.
+    pub fn set_frequency(&mut self, frequency: u16, correction: f32) -> Result<u8, PwmError> {
+        self.pwm
+            .lock()
+            .unwrap()
+            .set_frequency(&MOTOR_CHANNELS, frequency, correction)
+    }
+
+    /// Whether the channel is on the board of a motor and changes with its frequency.
+    pub fn shares_board(&self, channel: &str) -> bool {
+        let pwm = self.pwm.lock().unwrap();
+        MOTOR_CHANNELS
+            .iter()
+            .any(|motor| pwm.same_board(motor, channel))
+    }
+
+    // Lets the wheels coast, both stop in the same write when their channels are next to each other
     pub fn stop(&mut self) {
-        self.pca.set_duty_cycle(0, 0).unwrap();
-        self.pca.set_duty_cycle(1, 0).unwrap();
+        self.pwm
+            .lock()
+            .unwrap()
+            .set_duty_cycles(&MOTOR_CHANNELS, &[0, 0])
+            .unwrap();
+        self.duty_cycles = [0, 0];
         ()
     }
+
+    // Shorts the motors through the bridge: both inputs high with the output enabled
+    pub fn brake(&mut self) {
+        for side in &[Side::Left, Side::Right] {
+            self.set_pins(*side, 1, 1);
+            self.set_duty_cycle(*side, FULL_DUTY_CYCLE);
+            self.directions[side_index(*side)] = None;
+            self.switching[side_index(*side)] = None;
+        }
+    }
 }
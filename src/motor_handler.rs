@@ -2,13 +2,30 @@ use futures::sync::mpsc;
 
 use tokio::prelude::*;
 
+use std::collections::VecDeque;
+use std::mem;
 use std::time::{Duration, Instant};
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::timer::Interval;
 
+use crate::autotune::AutoTune;
+use crate::calibration::{Calibration, Calibrator};
 use crate::command::{Direction, MotorCommand};
-use crate::event::{EncodersSnapshot, Event, MotorRunStat, TimedEvent};
-use crate::motor::{Dir, Motor, Side};
+use crate::config::Config;
+use crate::event::{
+    EncodersSnapshot, Event, MotionStatus, MotorFault, MotorRunStat, MotorStatus, RunEnd,
+    TimedEvent, WheelRunStat,
+};
+use crate::fault::FaultDetector;
+use crate::gains::{Pid, PidProfiles};
+use crate::geometry::Geometry;
+use crate::motor::{Dir, Motor, Side, MOTOR_CHANNELS};
+use crate::navigation::GoTo;
+use crate::odometry::PoseHandle;
+use crate::pca9685::{MAX_FREQUENCY, MIN_FREQUENCY};
+use crate::profile::{ramp, Profile, TickLimits};
+use crate::pwm::SharedPwm;
+use crate::trajectory::{Recorder, Trajectory};
 use std::sync::{Arc, Mutex};
 
 type Tx = mpsc::UnboundedSender<TimedEvent>;
@@ -24,165 +41,1194 @@ type TxEvent = mpsc::UnboundedSender<EncodersSnapshot>;
 
 const HEARTBEAT_MS: u64 = 1000;
 
+// Encoder rate at full duty cycle, converts commanded speeds to target rates
+const MAX_TICKS_PER_SEC: f32 = 200.0;
+// Keeps the heading straight by correcting the wheels' relative progress
+const COUPLING_GAIN: f32 = 0.5;
+// Wheel targets whose ratio differs by less than this steer the same way
+const RATIO_TOLERANCE: f32 = 0.01;
+// Control steps kept for the summary of a run
+const MAX_RUN_STATS: usize = 1000;
+// How often a `GoTo` reports its progress
+const PROGRESS_MS: u128 = 250;
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MotionMode {
+    // Fixed number of ticks in one of the `Direction`s
+    Ticks,
+    // Continuous velocity, runs until replaced, stopped or the heartbeat expires
+    Velocity,
+    // Relay feedback experiment, drives forward until the oscillation is measured
+    AutoTune,
+    // Ramps the duty cycle of one wheel after the other to measure their speeds
+    Calibrate,
+    // Steers a velocity drive to a target pose
+    GoTo,
+}
+
 struct WheelState {
+    // ticks per second
+    target: f32,
     i_term: f32,
-    last_ticks: Option<isize>,
-    current_ticks: isize,
-    speed: f32,
+    last_speed: Option<f32>,
+    total_ticks: isize,
+    output: f32,
 }
 
 impl WheelState {
-    pub fn new(speed: f32) -> WheelState {
+    pub fn new(target: f32, output: f32) -> WheelState {
         WheelState {
+            target,
             i_term: 0.0,
-            last_ticks: None,
-            current_ticks: 0,
-            speed: speed,
+            last_speed: None,
+            total_ticks: 0,
+            output,
         }
     }
-    pub fn set_ticks(&mut self, ticks: isize) {
-        self.current_ticks = ticks;
-    }
-}
-
-struct BaseWheelState {
-    current_ticks: isize,
-    speed: f32,
-}
-
-impl BaseWheelState {
-    pub fn new(speed: f32) -> BaseWheelState {
-        BaseWheelState {
-            current_ticks: 0,
-            speed: speed,
-        }
-    }
-    pub fn set_ticks(&mut self, ticks: isize) {
-        self.current_ticks = ticks;
-    }
-}
-
-struct Pid {
-    p: f32,
-    i: f32,
-    d: f32,
 }
 
 struct MotorState {
+    mode: MotionMode,
     direction: Direction,
+    // Signed speed of each wheel relative to the profile of a move, (1, 1) drives straight ahead
+    ratios: (f32, f32),
     is_moving: bool,
     heartbeat_touch: u128,
     ticks_to_move: isize,
     ticks_moved: isize,
     pid: Pid,
+    profiles: PidProfiles,
     wheel_left: WheelState,
-    wheel_right: BaseWheelState,
+    wheel_right: WheelState,
     speed: u8,
+    linear: f32,
+    angular: f32,
+    // Control steps of the current run, reported when it ends
     motor_stats: Vec<MotorRunStat>,
+    // Sends every control step as it happens
+    stream_stats: bool,
+    geometry: Geometry,
+    limits: TickLimits,
+    profile: Profile,
+    // Signed wheel targets of a velocity drive, the wheels ramp towards them
+    drive_left: f32,
+    drive_right: f32,
+    queue: VecDeque<(u32, MotorCommand)>,
+    // Id of the queued motion being executed
+    current: Option<u32>,
+    // A paused move decelerates and holds until resumed
+    paused: bool,
+    next_motion_id: u32,
+    tuning: Option<AutoTune>,
+    calibration: Calibration,
+    calibrator: Option<Calibrator>,
+    goto: Option<GoTo>,
+    last_progress: u128,
+    pose: PoseHandle,
+    faults: FaultDetector,
+    // Set when a wheel stalled or the encoders disagreed, no motion starts until cleared
+    fault: Option<MotorFault>,
+    recorder: Option<Recorder>,
+    // The PWM frequency of the motors' boards can't change under these
+    servo_channels: Vec<String>,
+    tx: Arc<Mutex<Tx>>,
 }
 
 pub struct MotorHandler {
     rx_command: RxCommand,
     rx_event: RxEvent,
-    tx: Arc<Mutex<Tx>>,
     state: Arc<Mutex<MotorState>>,
     motor: Arc<Mutex<Option<Motor>>>,
 }
 
+/// Read-only view of the motor state for status queries.
+#[derive(Clone)]
+pub struct MotorStatusHandle {
+    state: Arc<Mutex<MotorState>>,
+}
+
+impl MotorStatusHandle {
+    pub fn get(&self) -> MotorStatus {
+        let state = self.state.lock().unwrap();
+        MotorStatus {
+            is_moving: state.is_moving,
+            mode: state.mode,
+            direction: state.direction,
+            speed: state.speed,
+            ticks_moved: state.ticks_moved,
+            ticks_remaining: (state.ticks_to_move - state.ticks_moved).max(0),
+            linear: state.linear,
+            angular: state.angular,
+            current: state.current,
+            queued: state.queue.len(),
+            paused: state.paused,
+            profile: state.profiles.active.clone(),
+            fault: state.fault.clone(),
+            recording: state
+                .recorder
+                .as_ref()
+                .map(|recorder| recorder.name().to_string()),
+            p: state.pid.p,
+            i: state.pid.i,
+            d: state.pid.d,
+        }
+    }
+}
+
+fn clamp(value: f32, min: f32, max: f32) -> f32 {
+    if value > max {
+        max
+    } else if value < min {
+        min
+    } else {
+        value
+    }
+}
+
+// Duty cycle which would give the target speed of an uncalibrated motor
+fn feed_forward(target: f32) -> f32 {
+    clamp(target / MAX_TICKS_PER_SEC * 100.0, 0.0, 100.0)
+}
+
 // http://brettbeauregard.com/blog/2011/04/improving-the-beginner%e2%80%99s-pid-reset-windup/
 fn next_wheel_state(
     ws: &WheelState,
-    base_wheel: &BaseWheelState,
     pid: &Pid,
+    ticks: isize,
     duration: isize,
-) -> (WheelState, MotorRunStat) {
-    let error = (base_wheel.current_ticks - ws.current_ticks) as f32;
-    let mut i_term = ws.i_term + pid.i * error;
-
-    let out_min = -(base_wheel.speed as f32);
-    let out_max = 100.0 - base_wheel.speed as f32;
+    base: f32,
+    correction: f32,
+) -> (WheelState, WheelRunStat) {
+    let speed = ticks as f32 * 1000.0 / duration as f32;
+    let error = ws.target - speed;
 
-    if i_term > out_max {
-        i_term = out_max;
-    } else if i_term < out_min {
-        i_term = out_min;
-    }
-
-    let input_delta = ws.last_ticks.map_or(0, |last| ws.current_ticks - last);
+    let out_min = -base;
+    let out_max = 100.0 - base;
 
-    let mut output = pid.p * error + ws.i_term - pid.d * input_delta as f32;
+    let i_term = clamp(ws.i_term + pid.i * error, out_min, out_max);
 
-    if output > out_max {
-        output = out_max;
-    } else if output < out_min {
-        output = out_min;
-    }
+    // derivative on measurement avoids a kick when the target changes
+    let input_delta = ws.last_speed.map_or(0.0, |last| speed - last);
 
-    //    println!("speed {}, p_term: {}, i_term: {}, d_term: {}, error: {}, current_ticks: {}, base ticks: {}",
-    //        output, pid.p * error, i_term, pid.d * input_delta as f32, error, ws.current_ticks, base_wheel.current_ticks);
+    let p_term = pid.p * error;
+    let d_term = pid.d * input_delta;
+    let output = clamp(base + p_term + i_term - d_term + correction, 0.0, 100.0);
 
     let wheel_state = WheelState {
-        i_term: i_term,
-        last_ticks: Some(ws.current_ticks),
-        current_ticks: 0,
-        speed: base_wheel.speed + output,
+        target: ws.target,
+        i_term,
+        last_speed: Some(speed),
+        total_ticks: ws.total_ticks + ticks,
+        output,
     };
 
-    let stat = MotorRunStat {
-        speed_base: base_wheel.speed,
-        speed_slave: wheel_state.speed,
-        ticks_base: base_wheel.current_ticks as isize,
-        ticks_slave: ws.current_ticks as isize,
-        error: error,
-        p_term: pid.p * error,
-        i_term: i_term,
-        d_term: pid.d * input_delta as f32,
-        duration,
+    let stat = WheelRunStat {
+        target: ws.target,
+        speed,
+        ticks,
+        error,
+        p_term,
+        i_term,
+        d_term,
+        output,
     };
 
     (wheel_state, stat)
 }
 
+// How far, in ticks, the left wheel is ahead of where it should be relative to the right one.
+// Wheels with different targets (arcs) are compared in proportion to their targets.
+fn coupling_error(
+    left_ticks: isize,
+    right_ticks: isize,
+    left_target: f32,
+    right_target: f32,
+) -> f32 {
+    let fastest = left_target.max(right_target);
+    if fastest <= 0.0 {
+        return 0.0;
+    }
+
+    (left_ticks as f32 * right_target - right_ticks as f32 * left_target) / fastest
+}
+
+// v_left = v - ω * W / 2, v_right = v + ω * W / 2, as a signed percentage of full speed.
+// Both wheels are scaled down together when one of them can't go fast enough.
+fn wheel_speeds(geometry: &Geometry, linear: f32, angular: f32) -> (f32, f32) {
+    let track_width = geometry.track_width_mm / 1000.0;
+    let max_wheel_speed = MAX_TICKS_PER_SEC * geometry.mm_per_tick() / 1000.0;
+
+    let left = (linear - angular * track_width / 2.0) / max_wheel_speed * 100.0;
+    let right = (linear + angular * track_width / 2.0) / max_wheel_speed * 100.0;
+
+    let fastest = left.abs().max(right.abs());
+    if fastest > 100.0 {
+        (left * 100.0 / fastest, right * 100.0 / fastest)
+    } else {
+        (left, right)
+    }
+}
+
+// Inverse of `wheel_speeds`, velocities in m/s and rad/s of signed wheel percentages
+fn body_velocity(geometry: &Geometry, left: f32, right: f32) -> (f32, f32) {
+    let track_width = geometry.track_width_mm / 1000.0;
+    let max_wheel_speed = MAX_TICKS_PER_SEC * geometry.mm_per_tick() / 1000.0;
+
+    let left = left / 100.0 * max_wheel_speed;
+    let right = right / 100.0 * max_wheel_speed;
+
+    ((left + right) / 2.0, (right - left) / track_width)
+}
+
+// Signed wheel speeds relative to the profile speed
+fn direction_ratios(direction: Direction) -> (f32, f32) {
+    match direction {
+        Direction::Forward => (1.0, 1.0),
+        Direction::Backward => (-1.0, -1.0),
+        Direction::Right => (1.0, -1.0),
+        Direction::Left => (-1.0, 1.0),
+    }
+}
+
+// Distance covered in ticks of a wheel running at the full profile speed,
+// the average of both wheels for straight moves and turns.
+fn progress(left_ticks: isize, right_ticks: isize, ratios: (f32, f32)) -> isize {
+    ((left_ticks + right_ticks) as f32 / (ratios.0.abs() + ratios.1.abs())) as isize
+}
+
+fn set_directions(motor: &mut Motor, state: &MotorState) {
+    let (left, right) = state.wheel_signs();
+    for (side, sign) in &[(Side::Left, left), (Side::Right, right)] {
+        if *sign < 0 {
+            motor.set_direction(*side, Dir::Backward);
+        } else {
+            motor.set_direction(*side, Dir::Forward);
+        }
+    }
+}
+
+fn apply_outputs(motor: &mut Motor, state: &MotorState) {
+    motor.set_speed(Side::Left, state.wheel_left.output);
+    motor.set_speed(Side::Right, state.wheel_right.output);
+}
+
+fn same_direction(a: f32, b: f32) -> bool {
+    (a < 0.0) == (b < 0.0)
+}
+
+// Whether two pairs of wheel targets steer the same way, whatever their speed
+fn same_ratio(a: (f32, f32), b: (f32, f32)) -> bool {
+    let a_fastest = a.0.abs().max(a.1.abs());
+    let b_fastest = b.0.abs().max(b.1.abs());
+    if a_fastest <= 0.0 || b_fastest <= 0.0 {
+        return a_fastest == b_fastest;
+    }
+
+    (a.0 / a_fastest - b.0 / b_fastest).abs() < RATIO_TOLERANCE
+        && (a.1 / a_fastest - b.1 / b_fastest).abs() < RATIO_TOLERANCE
+}
+
+// Everything which would drive the wheels is refused while there is a fault
+fn needs_motors(command: &MotorCommand) -> bool {
+    match command {
+        MotorCommand::Move { .. }
+        | MotorCommand::MoveDistance { .. }
+        | MotorCommand::Rotate { .. }
+        | MotorCommand::Arc { .. }
+        | MotorCommand::GoTo { .. }
+        | MotorCommand::Drive { .. }
+        | MotorCommand::Wheels { .. }
+        | MotorCommand::WheelTicks { .. }
+        | MotorCommand::AutoTune { .. }
+        | MotorCommand::Calibrate
+        | MotorCommand::Enqueue { .. }
+        | MotorCommand::Replay { .. }
+        | MotorCommand::Resume => true,
+        _ => false,
+    }
+}
+
+fn is_drive(command: &MotorCommand) -> bool {
+    match command {
+        MotorCommand::Drive { .. } | MotorCommand::Wheels { .. } => true,
+        _ => false,
+    }
+}
+
+// Only motions with a defined end can be queued, and a pause has to be able to hold them
+fn is_queueable(command: &MotorCommand) -> bool {
+    match command {
+        MotorCommand::Move { .. }
+        | MotorCommand::MoveDistance { .. }
+        | MotorCommand::Rotate { .. }
+        | MotorCommand::Arc { .. }
+        | MotorCommand::WheelTicks { .. } => true,
+        _ => false,
+    }
+}
+
+// Direction of the wheel pair for signed wheel ratios, spins are named by the way they turn
+fn ratios_direction(ratios: (f32, f32)) -> Direction {
+    if ratios.0 >= 0.0 && ratios.1 >= 0.0 {
+        Direction::Forward
+    } else if ratios.0 <= 0.0 && ratios.1 <= 0.0 {
+        Direction::Backward
+    } else if ratios.0 > 0.0 {
+        Direction::Right
+    } else {
+        Direction::Left
+    }
+}
+
+fn start_motion(motor_option: &mut Option<Motor>, state: &mut MotorState, command: MotorCommand) {
+    // A drive steered many times a second is recorded as one segment until it stops
+    let continues = state.is_moving && state.is_velocity_mode() && is_drive(&command);
+    if let Some(recorder) = state.recorder.as_mut() {
+        if !continues {
+            recorder.begin(&command);
+        }
+    }
+
+    match command {
+        MotorCommand::Move {
+            speed,
+            direction,
+            ticks,
+            p,
+            i,
+            d,
+        } => {
+            let active = state.profiles.active();
+            let pid = Pid {
+                p: p.unwrap_or(active.p),
+                i: i.unwrap_or(active.i),
+                d: d.unwrap_or(active.d),
+            };
+            state.new_command(direction, direction_ratios(direction), speed, ticks, pid);
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        MotorCommand::MoveDistance { mm, speed } => {
+            let direction = if mm < 0.0 {
+                Direction::Backward
+            } else {
+                Direction::Forward
+            };
+            let ticks = state.geometry.ticks_for_distance(mm);
+
+            let pid = state.profiles.active();
+            state.new_command(direction, direction_ratios(direction), speed, ticks, pid);
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        MotorCommand::Rotate { degrees, speed } => {
+            let direction = if degrees < 0.0 {
+                Direction::Right
+            } else {
+                Direction::Left
+            };
+            let ticks = state.geometry.ticks_for_rotation(degrees);
+
+            let pid = state.profiles.active();
+            state.new_command(direction, direction_ratios(direction), speed, ticks, pid);
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        MotorCommand::Arc {
+            radius_mm,
+            length_mm,
+            speed,
+        } => match state.geometry.arc(radius_mm, length_mm) {
+            Some((ratios, ticks)) => {
+                let direction = if length_mm < 0.0 {
+                    Direction::Backward
+                } else {
+                    Direction::Forward
+                };
+
+                let pid = state.profiles.active();
+                state.new_command(direction, ratios, speed, ticks, pid);
+
+                motor_option.as_mut().map(|motor| {
+                    set_directions(motor, state);
+                    apply_outputs(motor, state);
+                });
+            }
+            None => state.send(Event::Error {
+                message: "An arc needs a radius, use rotate to turn in place".to_string(),
+            }),
+        },
+        MotorCommand::Drive { linear, angular } => {
+            let (left, right) = wheel_speeds(&state.geometry, linear, angular);
+
+            state.new_drive(linear, angular, left, right);
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        MotorCommand::GoTo { x, y, theta, speed } => {
+            state.new_goto(x, y, theta, speed);
+            state.navigate();
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        MotorCommand::Wheels { left, right } => {
+            let left = clamp(left, -100.0, 100.0);
+            let right = clamp(right, -100.0, 100.0);
+            let (linear, angular) = body_velocity(&state.geometry, left, right);
+
+            state.new_drive(linear, angular, left, right);
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        MotorCommand::WheelTicks { left, right, speed } => {
+            let ticks = left.abs().max(right.abs());
+            if ticks == 0 {
+                state.send(Event::Error {
+                    message: "Wheel ticks need a wheel to turn".to_string(),
+                });
+                return;
+            }
+
+            let ratios = (left as f32 / ticks as f32, right as f32 / ticks as f32);
+            let pid = state.profiles.active();
+            state.new_command(ratios_direction(ratios), ratios, speed, ticks as u32, pid);
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        MotorCommand::AutoTune {
+            speed,
+            amplitude,
+            save,
+        } => {
+            state.new_autotune(speed, amplitude, save);
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        MotorCommand::Calibrate => {
+            state.new_calibration();
+
+            motor_option.as_mut().map(|motor| {
+                set_directions(motor, state);
+                apply_outputs(motor, state);
+            });
+        }
+        _ => println!("Not a motion command"),
+    }
+}
+
+fn start_next(motor_option: &mut Option<Motor>, state: &mut MotorState) {
+    if state.paused || state.current.is_some() {
+        return;
+    }
+
+    // A manual motion with an end is finished first, this is called again when it ends.
+    // A velocity drive never ends by itself, the queue takes over from it.
+    if state.is_moving {
+        if !state.is_velocity_mode() {
+            return;
+        }
+        state.end_run(RunEnd::Replaced);
+        state.is_moving = false;
+    }
+
+    while let Some((id, command)) = state.queue.pop_front() {
+        println!("Starting queued motion {}", id);
+        state.current = Some(id);
+        state.send(Event::Motion {
+            id,
+            status: MotionStatus::Started,
+        });
+        start_motion(motor_option, state, command);
+
+        if state.is_moving {
+            return;
+        }
+        // Couldn't be started, carry on with the next one
+        state.end_current(MotionStatus::Aborted);
+    }
+}
+
 impl MotorState {
-    pub fn new() -> MotorState {
+    pub fn new(config: &Config, pose: PoseHandle, tx: Arc<Mutex<Tx>>) -> MotorState {
+        let limits = TickLimits::new(&config.motion, &config.geometry);
+        let profiles = PidProfiles::load();
+
         MotorState {
+            mode: MotionMode::Ticks,
             direction: Direction::Forward,
+            ratios: (1.0, 1.0),
             is_moving: false,
             heartbeat_touch: 0,
             ticks_to_move: 0,
             ticks_moved: 0,
-            pid: Pid {
-                p: 0.0,
-                i: 0.0,
-                d: 0.0,
-            },
+            pid: profiles.active(),
+            profiles,
             speed: 0,
-            wheel_left: WheelState::new(0.0),
-            wheel_right: BaseWheelState::new(0.0),
+            linear: 0.0,
+            angular: 0.0,
+            wheel_left: WheelState::new(0.0, 0.0),
+            wheel_right: WheelState::new(0.0, 0.0),
             motor_stats: Vec::new(),
+            stream_stats: false,
+            geometry: config.geometry.clone(),
+            limits,
+            profile: Profile::new(0.0, limits),
+            drive_left: 0.0,
+            drive_right: 0.0,
+            queue: VecDeque::new(),
+            current: None,
+            paused: false,
+            next_motion_id: 1,
+            tuning: None,
+            calibration: Calibration::load(),
+            calibrator: None,
+            goto: None,
+            last_progress: 0,
+            pose,
+            faults: FaultDetector::new(&config.faults, &config.geometry),
+            fault: None,
+            recorder: None,
+            servo_channels: config
+                .servos
+                .iter()
+                .map(|(name, servo)| servo.pwm_channel(name))
+                .collect(),
+            tx,
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let tx = self.tx.lock().unwrap();
+        match tx.unbounded_send(TimedEvent::new(event)) {
+            Ok(_) => (),
+            Err(e) => println!("motor event send error = {:?}", e),
+        }
+    }
+
+    // Persists the profiles and tells the clients about them
+    pub fn save_profiles(&self) {
+        if let Err(e) = self.profiles.save() {
+            println!("Could not save PID profiles = {:?}", e);
+            self.send(Event::Error {
+                message: format!("Could not save PID profiles: {}", e),
+            });
+        }
+        self.send_profiles();
+    }
+
+    pub fn send_profiles(&self) {
+        self.send(Event::PidProfiles {
+            active: self.profiles.active.clone(),
+            profiles: self.profiles.profiles.clone(),
+        });
+    }
+
+    pub fn record(&mut self, stat: MotorRunStat) {
+        if self.stream_stats {
+            self.send(Event::MotorRunStat { stat: stat.clone() });
+        }
+
+        // A long drive only reports its latest steps
+        if self.motor_stats.len() >= MAX_RUN_STATS {
+            self.motor_stats.remove(0);
+        }
+        self.motor_stats.push(stat);
+    }
+
+    // Reports the steps of the run which just ended and starts over for the next one
+    pub fn end_run(&mut self, end: RunEnd) {
+        let stats = mem::replace(&mut self.motor_stats, Vec::new());
+        self.send(Event::MotorRunStats {
+            stats,
+            end,
+            p: self.pid.p,
+            i: self.pid.i,
+            d: self.pid.d,
+        });
+    }
+
+    // Ends the motion and everything queued after it
+    pub fn set_fault(&mut self, fault: MotorFault) {
+        println!("Motor fault, stopped moving");
+        if self.is_moving {
+            self.end_run(RunEnd::Fault);
+        }
+        self.is_moving = false;
+        self.end_current(MotionStatus::Aborted);
+        self.clear_queue();
+        self.send(Event::MotorFault {
+            fault: fault.clone(),
+        });
+        self.fault = Some(fault);
+    }
+
+    pub fn start_recording(&mut self, name: &str) {
+        match Recorder::new(name) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e) => self.send(Event::Error {
+                message: format!("Can't record trajectory '{}': {}", name, e),
+            }),
+        }
+    }
+
+    pub fn finish_recording(&mut self) {
+        let recorder = match self.recorder.take() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+
+        let (name, trajectory) = recorder.finish();
+        if let Err(e) = trajectory.save(&name) {
+            println!("Could not save trajectory {} = {:?}", name, e);
+            self.send(Event::Error {
+                message: format!("Could not save trajectory '{}': {}", name, e),
+            });
+            return;
+        }
+        self.send(Event::Trajectory {
+            name,
+            segments: trajectory.segments.len(),
+        });
+    }
+
+    // Queues the segments as moves over their measured ticks, so the encoders close the loop
+    pub fn replay(&mut self, trajectory: &Trajectory, reverse: bool, speed: Option<u8>) {
+        for segment in trajectory.segments(reverse) {
+            let (left, right) = segment.ticks(reverse);
+            let recorded = clamp(segment.speed() / MAX_TICKS_PER_SEC * 100.0, 1.0, 100.0);
+            self.enqueue(MotorCommand::WheelTicks {
+                left,
+                right,
+                speed: speed.unwrap_or(recorded.round() as u8),
+            });
+        }
+    }
+
+    pub fn enqueue(&mut self, command: MotorCommand) -> u32 {
+        let id = self.next_motion_id;
+        self.next_motion_id += 1;
+
+        self.queue.push_back((id, command));
+        self.send(Event::Motion {
+            id,
+            status: MotionStatus::Queued,
+        });
+        id
+    }
+
+    pub fn clear_queue(&mut self) {
+        let aborted: Vec<u32> = self.queue.drain(..).map(|(id, _)| id).collect();
+        for id in aborted {
+            self.send(Event::Motion {
+                id,
+                status: MotionStatus::Aborted,
+            });
+        }
+    }
+
+    pub fn end_current(&mut self, status: MotionStatus) {
+        if let Some(id) = self.current.take() {
+            self.send(Event::Motion { id, status });
         }
     }
 
+    // A move which is paused and no longer driven
+    pub fn is_held(&self) -> bool {
+        self.paused && self.is_move()
+    }
+
+    // Continues a paused move from standstill over the remaining distance
+    pub fn resume_move(&mut self) {
+        self.profile = Profile::new(self.speed as f32 / 100.0 * MAX_TICKS_PER_SEC, self.limits);
+
+        let mut left = self.wheel_state(Side::Left, self.profile.speed() * self.ratios.0.abs());
+        left.total_ticks = self.wheel_left.total_ticks;
+        let mut right = self.wheel_state(Side::Right, self.profile.speed() * self.ratios.1.abs());
+        right.total_ticks = self.wheel_right.total_ticks;
+
+        self.wheel_left = left;
+        self.wheel_right = right;
+        self.faults.reset();
+        self.heartbeat_touch = get_millis();
+    }
+
     pub fn new_command(
         &mut self,
         direction: Direction,
+        ratios: (f32, f32),
         speed: u8,
         ticks_to_move: u32,
-        p: f32,
-        i: f32,
-        d: f32,
+        pid: Pid,
     ) {
+        self.mode = MotionMode::Ticks;
         self.is_moving = true;
         self.ticks_to_move = ticks_to_move as isize;
         self.direction = direction;
-        self.pid = Pid { p: p, i: i, d: d };
+        self.ratios = ratios;
+        self.pid = pid;
 
-        self.wheel_left = WheelState::new(speed as f32);
-        self.wheel_right = BaseWheelState::new(speed as f32);
+        self.profile = Profile::new(speed as f32 / 100.0 * MAX_TICKS_PER_SEC, self.limits);
+        self.wheel_left = self.wheel_state(Side::Left, self.profile.speed() * ratios.0.abs());
+        self.wheel_right = self.wheel_state(Side::Right, self.profile.speed() * ratios.1.abs());
         self.speed = speed;
         self.ticks_moved = 0;
+        self.motor_stats.clear();
+        self.faults.reset();
+        self.heartbeat_touch = get_millis();
+    }
+
+    pub fn new_drive(&mut self, linear: f32, angular: f32, left: f32, right: f32) {
+        let left = left / 100.0 * MAX_TICKS_PER_SEC;
+        let right = right / 100.0 * MAX_TICKS_PER_SEC;
+
+        let driving = self.is_moving && self.is_velocity_mode();
+        if !driving {
+            self.pid = self.profiles.active();
+            self.faults.reset();
+            self.motor_stats.clear();
+        }
+        self.set_drive(driving, linear, angular, left, right);
+
+        self.mode = MotionMode::Velocity;
+        self.is_moving = true;
+        self.ticks_to_move = 0;
+        self.ticks_moved = 0;
         self.heartbeat_touch = get_millis();
     }
+
+    // A running drive keeps its wheel speeds and ramps to the new targets,
+    // a wheel which changes direction has to start from standstill.
+    // Speeding up or slowing down keeps the coupling, steering starts it over.
+    fn set_drive(&mut self, driving: bool, linear: f32, angular: f32, left: f32, right: f32) {
+        let left_turns = !driving || !same_direction(left, self.drive_left);
+        let right_turns = !driving || !same_direction(right, self.drive_right);
+        if left_turns {
+            self.wheel_left = WheelState::new(0.0, 0.0);
+        }
+        if right_turns {
+            self.wheel_right = WheelState::new(0.0, 0.0);
+        }
+        let steers = !same_ratio((left, right), (self.drive_left, self.drive_right));
+        if left_turns || right_turns || steers {
+            self.reset_coupling();
+        }
+
+        self.drive_left = left;
+        self.drive_right = right;
+        self.linear = linear;
+        self.angular = angular;
+    }
+
+    // The coupling compares the distances of both wheels since their targets last changed
+    fn reset_coupling(&mut self) {
+        self.wheel_left.total_ticks = 0;
+        self.wheel_right.total_ticks = 0;
+    }
+
+    pub fn new_goto(&mut self, x: f32, y: f32, theta: Option<f32>, speed: u8) {
+        let m_per_tick = self.geometry.mm_per_tick() / 1000.0;
+        let max_linear = speed as f32 / 100.0 * MAX_TICKS_PER_SEC * m_per_tick;
+
+        self.goto = Some(GoTo::new(
+            x,
+            y,
+            theta,
+            max_linear,
+            self.limits.min_speed * m_per_tick,
+            self.limits.deceleration * m_per_tick,
+        ));
+
+        self.mode = MotionMode::GoTo;
+        self.is_moving = true;
+        self.pid = self.profiles.active();
+        self.set_drive(false, 0.0, 0.0, 0.0, 0.0);
+        self.speed = speed;
+        self.ticks_to_move = 0;
+        self.ticks_moved = 0;
+        self.last_progress = 0;
+        self.motor_stats.clear();
+        self.faults.reset();
+        self.heartbeat_touch = get_millis();
+    }
+
+    // Sets the drive towards the target from the latest pose, returns false once arrived
+    pub fn navigate(&mut self) -> bool {
+        let pose = self.pose.get();
+        let goto = match self.goto.as_mut() {
+            Some(goto) => goto,
+            None => return false,
+        };
+
+        let previous_phase = goto.phase();
+        let velocity = goto.step(&pose);
+        let phase = goto.phase();
+        let distance = goto.distance(&pose);
+
+        let now = get_millis();
+        if phase != previous_phase || now - self.last_progress >= PROGRESS_MS {
+            self.last_progress = now;
+            self.send(Event::GoTo { phase, distance });
+        }
+        if phase != previous_phase {
+            self.reset_coupling();
+        }
+
+        match velocity {
+            Some((linear, angular)) => {
+                let (left, right) = wheel_speeds(&self.geometry, linear, angular);
+                self.set_drive(
+                    true,
+                    linear,
+                    angular,
+                    left / 100.0 * MAX_TICKS_PER_SEC,
+                    right / 100.0 * MAX_TICKS_PER_SEC,
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn new_autotune(&mut self, speed: u8, amplitude: u8, save: bool) {
+        let setpoint = speed as f32 / 100.0 * MAX_TICKS_PER_SEC;
+        let bias = (
+            self.wheel_duty(Side::Left, setpoint),
+            self.wheel_duty(Side::Right, setpoint),
+        );
+        let tuning = AutoTune::new(setpoint, bias, amplitude as f32, save);
+
+        self.mode = MotionMode::AutoTune;
+        self.direction = Direction::Forward;
+        self.is_moving = true;
+        self.wheel_left = WheelState::new(setpoint, tuning.left.output());
+        self.wheel_right = WheelState::new(setpoint, tuning.right.output());
+        self.tuning = Some(tuning);
+        self.speed = speed;
+        self.ticks_to_move = 0;
+        self.ticks_moved = 0;
+        self.motor_stats.clear();
+        self.faults.reset();
+        self.heartbeat_touch = get_millis();
+    }
+
+    // Switches the relays on the measured speeds, returns true once the experiment is over
+    pub fn autotune_step(&mut self, encoders: &EncodersSnapshot) -> bool {
+        self.wheel_left.total_ticks += encoders.left;
+        self.wheel_right.total_ticks += encoders.right;
+        self.ticks_moved = (self.wheel_left.total_ticks + self.wheel_right.total_ticks) / 2;
+
+        let tuning = match self.tuning.as_mut() {
+            Some(tuning) => tuning,
+            None => return true,
+        };
+
+        if encoders.duration <= 0 {
+            return tuning.is_done();
+        }
+
+        let fault = self.faults.check_stall(
+            self.wheel_left.output,
+            encoders.left,
+            self.wheel_right.output,
+            encoders.right,
+            encoders.duration,
+        );
+        if let Some(fault) = fault {
+            self.tuning = None;
+            self.set_fault(fault);
+            return true;
+        }
+
+        let dt = encoders.duration as f32 / 1000.0;
+        self.wheel_left.output = tuning.left.step(encoders.left as f32 / dt, dt);
+        self.wheel_right.output = tuning.right.step(encoders.right as f32 / dt, dt);
+
+        tuning.is_done()
+    }
+
+    // Reports the tuned gains, which are stored in the active profile when asked to save them
+    pub fn finish_autotune(&mut self) {
+        let tuning = match self.tuning.take() {
+            Some(tuning) => tuning,
+            None => return,
+        };
+
+        match tuning.result() {
+            Some(result) => {
+                if tuning.save {
+                    let active = self.profiles.active.clone();
+                    self.profiles.set(&active, result.pid);
+                    self.pid = result.pid;
+                    self.save_profiles();
+                }
+
+                self.send(Event::AutoTune {
+                    p: result.pid.p,
+                    i: result.pid.i,
+                    d: result.pid.d,
+                    ultimate_gain: result.ultimate_gain,
+                    ultimate_period: result.ultimate_period,
+                    saved: tuning.save,
+                });
+            }
+            None => self.send(Event::Error {
+                message: "Auto-tune could not measure an oscillation".to_string(),
+            }),
+        }
+    }
+
+    pub fn new_calibration(&mut self) {
+        let calibrator = Calibrator::new();
+
+        self.mode = MotionMode::Calibrate;
+        self.direction = Direction::Forward;
+        self.is_moving = true;
+        self.wheel_left = WheelState::new(0.0, calibrator.duty());
+        self.wheel_right = WheelState::new(0.0, 0.0);
+        self.calibrator = Some(calibrator);
+        self.speed = 0;
+        self.ticks_to_move = 0;
+        self.ticks_moved = 0;
+        self.motor_stats.clear();
+        self.faults.reset();
+        self.heartbeat_touch = get_millis();
+    }
+
+    // Returns true once both wheels have been ramped
+    pub fn calibration_step(&mut self, encoders: &EncodersSnapshot) -> bool {
+        self.wheel_left.total_ticks += encoders.left;
+        self.wheel_right.total_ticks += encoders.right;
+        self.ticks_moved = (self.wheel_left.total_ticks + self.wheel_right.total_ticks) / 2;
+        // The ramp has an end, and a wheel standing still at a low duty cycle is expected
+        self.heartbeat_touch = get_millis();
+
+        let calibrator = match self.calibrator.as_mut() {
+            Some(calibrator) => calibrator,
+            None => return true,
+        };
+
+        // No stall check, each duty cycle of the ramp is held longer than the stall window
+        // and the wheels are expected to stand still below the dead zone
+        if encoders.duration <= 0 {
+            return false;
+        }
+
+        let ticks = match calibrator.side() {
+            Side::Left => encoders.left,
+            Side::Right => encoders.right,
+        };
+        let done = calibrator.step(ticks, encoders.duration);
+
+        let (left, right) = match calibrator.side() {
+            Side::Left => (calibrator.duty(), 0.0),
+            Side::Right => (0.0, calibrator.duty()),
+        };
+        self.wheel_left.output = left;
+        self.wheel_right.output = right;
+
+        done
+    }
+
+    // Keeps the previous calibration unless both wheels could be measured
+    pub fn finish_calibration(&mut self) {
+        let calibrator = match self.calibrator.take() {
+            Some(calibrator) => calibrator,
+            None => return,
+        };
+
+        let calibration = calibrator.result();
+        if calibration.left.is_none() || calibration.right.is_none() {
+            self.send(Event::Error {
+                message: "Calibration failed, a wheel didn't turn".to_string(),
+            });
+            return;
+        }
+
+        self.calibration = calibration;
+        if let Err(e) = self.calibration.save() {
+            println!("Could not save motor calibration = {:?}", e);
+            self.send(Event::Error {
+                message: format!("Could not save motor calibration: {}", e),
+            });
+        }
+        self.send_calibration();
+    }
+
+    pub fn send_calibration(&self) {
+        self.send(Event::Calibration {
+            left: self.calibration.left.clone(),
+            right: self.calibration.right.clone(),
+        });
+    }
+
+    // Duty cycle which would give the target speed without any correction
+    fn wheel_duty(&self, side: Side, target: f32) -> f32 {
+        self.calibration
+            .duty(side, target)
+            .unwrap_or_else(|| feed_forward(target))
+    }
+
+    fn wheel_state(&self, side: Side, target: f32) -> WheelState {
+        WheelState::new(target, self.wheel_duty(side, target))
+    }
+
+    // The wheels keep their ratio from one target change to the next,
+    // a `GoTo` steers on every step so its coupling error has no baseline
+    fn is_coupled(&self) -> bool {
+        match self.mode {
+            MotionMode::Ticks | MotionMode::Velocity => true,
+            MotionMode::GoTo | MotionMode::AutoTune | MotionMode::Calibrate => false,
+        }
+    }
+
+    fn is_velocity_mode(&self) -> bool {
+        match self.mode {
+            MotionMode::Velocity => true,
+            MotionMode::Ticks | MotionMode::AutoTune | MotionMode::Calibrate | MotionMode::GoTo => {
+                false
+            }
+        }
+    }
+
+    // A move over a fixed number of ticks, the only motion which can be paused
+    fn is_move(&self) -> bool {
+        match self.mode {
+            MotionMode::Ticks => true,
+            MotionMode::Velocity
+            | MotionMode::AutoTune
+            | MotionMode::Calibrate
+            | MotionMode::GoTo => false,
+        }
+    }
+
+    // +1 for a wheel which is meant to turn forward, -1 for backward
+    fn wheel_signs(&self) -> (isize, isize) {
+        match self.mode {
+            MotionMode::Ticks => (
+                if self.ratios.0 < 0.0 { -1 } else { 1 },
+                if self.ratios.1 < 0.0 { -1 } else { 1 },
+            ),
+            MotionMode::Velocity | MotionMode::GoTo => (
+                if self.drive_left < 0.0 { -1 } else { 1 },
+                if self.drive_right < 0.0 { -1 } else { 1 },
+            ),
+            MotionMode::AutoTune | MotionMode::Calibrate => (1, 1),
+        }
+    }
+
+    // Wheel totals are always updated, speeds only when the snapshot has a duration.
+    // Ticks are counted along the commanded direction, so turning the wrong way counts as negative.
+    pub fn control_step(&mut self, encoders: &EncodersSnapshot) -> Option<MotorRunStat> {
+        let (left_sign, right_sign) = self.wheel_signs();
+        let left_ticks = encoders.left * left_sign;
+        let right_ticks = encoders.right * right_sign;
+
+        if encoders.duration <= 0 {
+            self.wheel_left.total_ticks += left_ticks;
+            self.wheel_right.total_ticks += right_ticks;
+            return None;
+        }
+
+        let dt = encoders.duration as f32 / 1000.0;
+        match self.mode {
+            MotionMode::Ticks if self.paused => {
+                self.wheel_left.target = ramp(self.wheel_left.target, 0.0, self.limits, dt);
+                self.wheel_right.target = ramp(self.wheel_right.target, 0.0, self.limits, dt);
+            }
+            MotionMode::Ticks => {
+                let moved = progress(
+                    self.wheel_left.total_ticks + left_ticks,
+                    self.wheel_right.total_ticks + right_ticks,
+                    self.ratios,
+                );
+                let speed = self.profile.next(dt, (self.ticks_to_move - moved) as f32);
+                self.wheel_left.target = speed * self.ratios.0.abs();
+                self.wheel_right.target = speed * self.ratios.1.abs();
+            }
+            MotionMode::Velocity | MotionMode::GoTo => {
+                self.wheel_left.target = ramp(
+                    self.wheel_left.target,
+                    self.drive_left.abs(),
+                    self.limits,
+                    dt,
+                );
+                self.wheel_right.target = ramp(
+                    self.wheel_right.target,
+                    self.drive_right.abs(),
+                    self.limits,
+                    dt,
+                );
+            }
+            // The experiments drive the wheels themselves
+            MotionMode::AutoTune | MotionMode::Calibrate => return None,
+        }
+
+        let coupling_error = coupling_error(
+            self.wheel_left.total_ticks + left_ticks,
+            self.wheel_right.total_ticks + right_ticks,
+            self.wheel_left.target,
+            self.wheel_right.target,
+        );
+        let coupling_term = COUPLING_GAIN * coupling_error;
+
+        let (left, left_stat) = next_wheel_state(
+            &self.wheel_left,
+            &self.pid,
+            left_ticks,
+            encoders.duration,
+            self.wheel_duty(Side::Left, self.wheel_left.target),
+            -coupling_term,
+        );
+        let (right, right_stat) = next_wheel_state(
+            &self.wheel_right,
+            &self.pid,
+            right_ticks,
+            encoders.duration,
+            self.wheel_duty(Side::Right, self.wheel_right.target),
+            coupling_term,
+        );
+        self.wheel_left = left;
+        self.wheel_right = right;
+
+        // Nothing should be pushing a held move, whatever the integral says
+        if self.is_held() && self.wheel_left.target <= 0.0 && self.wheel_right.target <= 0.0 {
+            self.wheel_left.output = 0.0;
+            self.wheel_right.output = 0.0;
+        }
+
+        Some(MotorRunStat {
+            left: left_stat,
+            right: right_stat,
+            coupling_error,
+            coupling_term,
+            duration: encoders.duration,
+        })
+    }
 }
 
 fn get_millis() -> u128 {
@@ -194,36 +1240,45 @@ fn get_millis() -> u128 {
 }
 
 impl MotorHandler {
-    pub fn new(tx: Arc<Mutex<Tx>>) -> (MotorHandler, TxCommand, TxEvent) {
+    pub fn new(
+        tx: Arc<Mutex<Tx>>,
+        config: &Config,
+        pose: PoseHandle,
+        pwm: SharedPwm,
+    ) -> (MotorHandler, TxCommand, TxEvent) {
         let (tx_command, rx_command) = mpsc::unbounded();
         let (tx_event, rx_event) = mpsc::unbounded();
+        let state = MotorState::new(config, pose, tx);
+        state.send_profiles();
+        state.send_calibration();
+        let state = Arc::new(Mutex::new(state));
 
-        match Motor::new() {
-            Ok(motor) => (
-                MotorHandler {
-                    rx_command,
-                    rx_event,
-                    tx,
-                    state: Arc::new(Mutex::new(MotorState::new())),
-                    motor: Arc::new(Mutex::new(Some(motor))),
-                },
-                tx_command,
-                tx_event,
-            ),
-            Err(e) => {
-                println!("Error creating a motor {:?}", e);
-                (
-                    MotorHandler {
-                        rx_command,
-                        rx_event,
-                        tx,
-                        state: Arc::new(Mutex::new(MotorState::new())),
-                        motor: Arc::new(Mutex::new(None)),
-                    },
-                    tx_command,
-                    tx_event,
-                )
-            }
+        let has_motors = {
+            let pwm = pwm.lock().unwrap();
+            MOTOR_CHANNELS.iter().all(|name| pwm.has_channel(name))
+        };
+        let motor = if has_motors {
+            Some(Motor::new(pwm))
+        } else {
+            println!("Error creating a motor, its PWM channels aren't available");
+            None
+        };
+
+        (
+            MotorHandler {
+                rx_command,
+                rx_event,
+                state,
+                motor: Arc::new(Mutex::new(motor)),
+            },
+            tx_command,
+            tx_event,
+        )
+    }
+
+    pub fn status_handle(&self) -> MotorStatusHandle {
+        MotorStatusHandle {
+            state: self.state.clone(),
         }
     }
 
@@ -234,50 +1289,228 @@ impl MotorHandler {
         let command_handler = self
             .rx_command
             .for_each(move |command| {
-                let mut motor_option = motor_command_arc.lock().unwrap();
                 let mut state = state_command_arc.lock().unwrap();
+                let mut motor_option = motor_command_arc.lock().unwrap();
+
+                if state.fault.is_some() && needs_motors(&command) {
+                    println!("Refusing motor command because of a fault");
+                    state.send(Event::Error {
+                        message: "Motor fault, send clearfault before moving again".to_string(),
+                    });
+                    return Ok(());
+                }
+
                 match command {
-                    MotorCommand::Move {
-                        speed,
-                        direction,
-                        ticks,
-                        p,
-                        i,
-                        d,
+                    MotorCommand::Enqueue { command } => {
+                        if is_queueable(&command) {
+                            let id = state.enqueue(*command);
+                            println!("Queued motion {}", id);
+                            start_next(&mut motor_option, &mut state);
+                        } else {
+                            state.send(Event::Error {
+                                message: "Only moves with a distance can be queued".to_string(),
+                            });
+                        }
+                    }
+                    MotorCommand::ClearQueue => {
+                        println!("Received motor clear queue command ");
+                        state.clear_queue();
+                    }
+                    MotorCommand::Pause => {
+                        println!("Received motor pause command ");
+                        state.paused = true;
+                    }
+                    MotorCommand::Resume => {
+                        println!("Received motor resume command ");
+                        state.paused = false;
+
+                        if state.is_moving && state.is_move() {
+                            state.resume_move();
+
+                            motor_option.as_mut().map(|motor| {
+                                set_directions(motor, &state);
+                                apply_outputs(motor, &state);
+                            });
+                        } else {
+                            start_next(&mut motor_option, &mut state);
+                        }
+                    }
+                    MotorCommand::Cancel => {
+                        println!("Received motor cancel command ");
+                        if state.current.is_some() {
+                            if state.is_moving {
+                                state.end_run(RunEnd::Stopped);
+                            }
+                            state.is_moving = false;
+                            motor_option.as_mut().map(|motor| motor.stop());
+                            state.end_current(MotionStatus::Aborted);
+                        }
+                        start_next(&mut motor_option, &mut state);
+                    }
+                    MotorCommand::SetGains { p, i, d, save } => {
+                        println!("Received motor set gains command ");
+                        state.pid = Pid {
+                            p: p.unwrap_or(state.pid.p),
+                            i: i.unwrap_or(state.pid.i),
+                            d: d.unwrap_or(state.pid.d),
+                        };
+
+                        if save {
+                            let active = state.profiles.active.clone();
+                            let pid = state.pid;
+                            state.profiles.set(&active, pid);
+                            state.save_profiles();
+                        }
+                    }
+                    MotorCommand::SaveProfile { name } => {
+                        println!("Received motor save profile command ");
+                        let pid = state.pid;
+                        state.profiles.set(&name, pid);
+                        state.save_profiles();
+                    }
+                    MotorCommand::SelectProfile { name } => {
+                        println!("Received motor select profile command ");
+                        if state.profiles.select(&name) {
+                            state.pid = state.profiles.active();
+                            state.save_profiles();
+                        } else {
+                            state.send(Event::Error {
+                                message: format!("No PID profile '{}'", name),
+                            });
+                        }
+                    }
+                    MotorCommand::DeleteProfile { name } => {
+                        println!("Received motor delete profile command ");
+                        if state.profiles.remove(&name) {
+                            state.save_profiles();
+                        } else {
+                            state.send(Event::Error {
+                                message: format!("Can't delete PID profile '{}'", name),
+                            });
+                        }
+                    }
+                    MotorCommand::Telemetry { enabled } => {
+                        println!("Received motor telemetry command ");
+                        state.stream_stats = enabled;
+                    }
+                    MotorCommand::PwmFrequency {
+                        frequency,
+                        correction,
                     } => {
-                        println!("Received motor Move command ");
+                        println!("Received motor pwm frequency command ");
+                        let correction = correction.unwrap_or(1.0);
+                        // The servos' pulse widths depend on the frequency of their board
+                        let servo = motor_option.as_ref().and_then(|motor| {
+                            state
+                                .servo_channels
+                                .iter()
+                                .find(|channel| motor.shares_board(channel))
+                                .cloned()
+                        });
+                        let message = if state.is_moving {
+                            Some("Can't change the PWM frequency while moving".to_string())
+                        } else if frequency < MIN_FREQUENCY || frequency > MAX_FREQUENCY {
+                            Some(format!(
+                                "PWM frequency has to be between {} and {} Hz",
+                                MIN_FREQUENCY, MAX_FREQUENCY
+                            ))
+                        } else if correction <= 0.0 {
+                            Some("PWM frequency correction has to be positive".to_string())
+                        } else if let Some(channel) = servo {
+                            Some(format!(
+                                "Can't change the PWM frequency, servo channel {} is on a motor board",
+                                channel
+                            ))
+                        } else {
+                            None
+                        };
 
-                        motor_option.as_mut().map(|motor| {
-                            match direction {
-                                Direction::Forward => {
-                                    motor.set_direction(Side::Left, Dir::Forward);
-                                    motor.set_direction(Side::Right, Dir::Forward);
-                                }
-                                Direction::Backward => {
-                                    motor.set_direction(Side::Left, Dir::Backward);
-                                    motor.set_direction(Side::Right, Dir::Backward);
-                                }
-                                Direction::Right => {
-                                    motor.set_direction(Side::Left, Dir::Forward);
-                                    motor.set_direction(Side::Right, Dir::Backward);
-                                }
-                                Direction::Left => {
-                                    motor.set_direction(Side::Left, Dir::Backward);
-                                    motor.set_direction(Side::Right, Dir::Forward);
+                        match (message, motor_option.as_mut()) {
+                            (Some(message), _) => state.send(Event::Error { message }),
+                            (None, Some(motor)) => match motor.set_frequency(frequency, correction)
+                            {
+                                Ok(prescale) => state.send(Event::PwmFrequency {
+                                    frequency,
+                                    correction,
+                                    prescale,
+                                }),
+                                Err(e) => state.send(Event::Error {
+                                    message: format!("Could not set the PWM frequency: {}", e),
+                                }),
+                            },
+                            (None, None) => state.send(Event::Error {
+                                message: "No motors to set the PWM frequency of".to_string(),
+                            }),
+                        }
+                    }
+                    MotorCommand::ClearFault => {
+                        println!("Received motor clear fault command ");
+                        state.fault = None;
+                        state.faults.reset();
+                    }
+                    MotorCommand::Record { name } => {
+                        println!("Received motor record command ");
+                        if state.recorder.is_some() {
+                            state.finish_recording();
+                        }
+                        state.start_recording(&name);
+                    }
+                    MotorCommand::StopRecording => {
+                        println!("Received motor stop recording command ");
+                        state.finish_recording();
+                    }
+                    MotorCommand::Replay {
+                        name,
+                        reverse,
+                        speed,
+                    } => {
+                        println!("Received motor replay command ");
+                        match Trajectory::load(&name) {
+                            Ok(trajectory) => {
+                                if state.is_moving {
+                                    state.end_run(RunEnd::Replaced);
                                 }
-                            };
-                            motor.set_speed(Side::Left, speed as f32);
-                            motor.set_speed(Side::Right, speed as f32);
-                        });
+                                state.is_moving = false;
+                                state.end_current(MotionStatus::Aborted);
+                                state.clear_queue();
+                                state.paused = false;
 
-                        state.new_command(direction, speed, ticks, p, i, d);
-                        ()
+                                state.replay(&trajectory, reverse, speed);
+                                start_next(&mut motor_option, &mut state);
+                            }
+                            Err(e) => state.send(Event::Error {
+                                message: format!("Can't replay trajectory '{}': {}", name, e),
+                            }),
+                        }
                     }
-                    MotorCommand::Stop => {
+                    MotorCommand::Stop | MotorCommand::Brake => {
                         println!("Received motor stop command ");
+                        if state.is_moving {
+                            state.end_run(RunEnd::Stopped);
+                        }
                         state.is_moving = false;
-                        motor_option.as_mut().map(|motor| motor.stop());
-                        ()
+                        motor_option.as_mut().map(|motor| match command {
+                            MotorCommand::Brake => motor.brake(),
+                            _ => motor.stop(),
+                        });
+                        state.end_current(MotionStatus::Aborted);
+                        state.clear_queue();
+                    }
+                    command => {
+                        println!("Received motor motion command ");
+
+                        // A new `Drive` carries on with the running drive
+                        let continues = state.is_velocity_mode() && is_drive(&command);
+                        if state.is_moving && !continues {
+                            state.end_run(RunEnd::Replaced);
+                        }
+
+                        // Manual control takes over from the queue
+                        state.end_current(MotionStatus::Aborted);
+                        state.clear_queue();
+                        state.paused = false;
+
+                        start_motion(&mut motor_option, &mut state, command);
                     }
                 };
 
@@ -289,63 +1522,146 @@ impl MotorHandler {
 
         let state_encoder_arc = self.state.clone();
         let motor_pid_arc = self.motor.clone();
-        //        let state_pid_arc = self.state.clone();
-        let tx = self.tx.clone();
         let encoder_handler = self
             .rx_event
             .for_each(move |encoders| {
                 let mut state = state_encoder_arc.lock().unwrap();
 
+                // Rolling out after a motion still belongs to its segment
+                if let Some(recorder) = state.recorder.as_mut() {
+                    recorder.add(&encoders);
+                }
+
                 if !state.is_moving {
                     return Ok(());
                 }
 
-                state.heartbeat_touch = get_millis();
-                state.ticks_moved += encoders.left as isize;
+                println!(
+                    "left ticks: {}, right ticks: {}",
+                    encoders.left, encoders.right
+                );
+
+                // Only wheels which actually turn keep a move alive
+                let turning = encoders.left != 0 || encoders.right != 0;
+
+                // Experiments drive the wheels themselves until they are done
+                let experiment = match state.mode {
+                    MotionMode::AutoTune => {
+                        if turning {
+                            state.heartbeat_touch = get_millis();
+                        }
+                        Some(state.autotune_step(&encoders))
+                    }
+                    MotionMode::Calibrate => Some(state.calibration_step(&encoders)),
+                    MotionMode::Ticks | MotionMode::Velocity | MotionMode::GoTo => None,
+                };
 
-                if state.ticks_moved >= state.ticks_to_move {
+                if let Some(done) = experiment {
                     let mut motor_option = motor_pid_arc.lock().unwrap();
-                    motor_option.as_mut().map(|motor| motor.stop());
+                    if done {
+                        motor_option.as_mut().map(|motor| motor.stop());
 
-                    println!("Finished moving");
-                    state.is_moving = false;
-                    let tx = tx.lock().unwrap();
-                    match tx.unbounded_send(TimedEvent::new(Event::MotorRunStats {
-                        stats: state.motor_stats.clone(),
-                        p: state.pid.p,
-                        i: state.pid.i,
-                        d: state.pid.d,
-                    })) {
-                        Ok(_) => (),
-                        Err(e) => println!("motor stats send error = {:?}", e),
-                    };
-                    state.motor_stats = Vec::new();
+                        // A fault has already ended the run
+                        if state.is_moving {
+                            state.end_run(RunEnd::Completed);
+                        }
+                        state.is_moving = false;
+                        match state.mode {
+                            MotionMode::AutoTune => {
+                                println!("Finished auto-tune");
+                                state.finish_autotune();
+                            }
+                            _ => {
+                                println!("Finished calibration");
+                                state.finish_calibration();
+                            }
+                        }
+                        start_next(&mut motor_option, &mut state);
+                    } else {
+                        motor_option
+                            .as_mut()
+                            .map(|motor| apply_outputs(motor, &state));
+                    }
                     return Ok(());
                 }
 
-                state.wheel_left.set_ticks(encoders.left as isize);
-                state.wheel_right.set_ticks(encoders.right as isize);
+                // Steers from the latest pose, the speed loop below follows the new targets
+                if let MotionMode::GoTo = state.mode {
+                    if turning {
+                        state.heartbeat_touch = get_millis();
+                    }
 
-                println!(
-                    "left ticks: {}, right ticks: {}",
-                    state.wheel_left.current_ticks, state.wheel_right.current_ticks
-                );
+                    let mut motor_option = motor_pid_arc.lock().unwrap();
+                    if !state.navigate() {
+                        motor_option.as_mut().map(|motor| motor.stop());
 
-                let (next_state, stat) = next_wheel_state(
-                    &state.wheel_left,
-                    &state.wheel_right,
-                    &state.pid,
-                    encoders.duration,
-                );
-                state.wheel_left = next_state;
-                state.wheel_right.current_ticks = 0;
-                state.motor_stats.push(stat);
+                        println!("Arrived at the target");
+                        state.is_moving = false;
+                        state.end_run(RunEnd::Completed);
+
+                        state.end_current(MotionStatus::Finished);
+                        start_next(&mut motor_option, &mut state);
+                        return Ok(());
+                    }
+                    motor_option
+                        .as_mut()
+                        .map(|motor| set_directions(motor, &state));
+                }
+
+                let stat = state.control_step(&encoders);
+                let adjusted = stat.is_some();
+
+                let fault = match &stat {
+                    Some(stat) if !state.is_held() => {
+                        let coupled = state.is_coupled();
+                        state.faults.check(stat, coupled)
+                    }
+                    _ => None,
+                };
+                if let Some(stat) = stat {
+                    state.record(stat);
+                }
+
+                if let Some(fault) = fault {
+                    let mut motor_option = motor_pid_arc.lock().unwrap();
+                    motor_option.as_mut().map(|motor| motor.stop());
+                    state.set_fault(fault);
+                    return Ok(());
+                }
+
+                // Only new `Drive` commands keep a velocity drive alive
+                if let MotionMode::Ticks = state.mode {
+                    if turning {
+                        state.heartbeat_touch = get_millis();
+                    }
+                    state.ticks_moved = progress(
+                        state.wheel_left.total_ticks,
+                        state.wheel_right.total_ticks,
+                        state.ratios,
+                    );
+
+                    if state.ticks_moved >= state.ticks_to_move {
+                        let mut motor_option = motor_pid_arc.lock().unwrap();
+                        motor_option.as_mut().map(|motor| motor.stop());
+
+                        println!("Finished moving");
+                        state.is_moving = false;
+                        state.end_run(RunEnd::Completed);
+
+                        state.end_current(MotionStatus::Finished);
+                        start_next(&mut motor_option, &mut state);
+                        return Ok(());
+                    }
+                }
+
+                if !adjusted {
+                    return Ok(());
+                }
 
                 let mut motor_option = motor_pid_arc.lock().unwrap();
-                motor_option.as_mut().map(|motor| {
-                    motor.set_speed(Side::Left, state.wheel_left.speed);
-                    motor.set_speed(Side::Right, state.wheel_right.speed);
-                });
+                motor_option
+                    .as_mut()
+                    .map(|motor| apply_outputs(motor, &state));
 
                 Ok(())
             })
@@ -359,7 +1675,7 @@ impl MotorHandler {
             .for_each(move |_| {
                 let mut state = state_pid_arc.lock().unwrap();
 
-                if !state.is_moving {
+                if !state.is_moving || state.is_held() {
                     return Ok(());
                 }
 
@@ -368,7 +1684,10 @@ impl MotorHandler {
                     motor_option.as_mut().map(|motor| motor.stop());
 
                     println!("Stopped moving because of heartbeat");
+                    state.end_run(RunEnd::Heartbeat);
                     state.is_moving = false;
+                    state.end_current(MotionStatus::Aborted);
+                    state.clear_queue();
                 }
 
                 Ok(())
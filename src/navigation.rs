@@ -0,0 +1,106 @@
+use crate::event::{GoToPhase, Pose};
+use crate::odometry::normalize_angle;
+
+const POSITION_TOLERANCE_MM: f32 = 20.0;
+// radians
+const HEADING_TOLERANCE: f32 = 0.05;
+// Turns on the spot again when the target is further off to the side while driving
+const DRIVE_HEADING: f32 = 0.5;
+// rad/s per radian of heading error
+const HEADING_GAIN: f32 = 2.0;
+const MIN_ANGULAR: f32 = 0.3;
+const MAX_ANGULAR: f32 = 1.5;
+
+fn turn_rate(error: f32) -> f32 {
+    let rate = (HEADING_GAIN * error.abs())
+        .max(MIN_ANGULAR)
+        .min(MAX_ANGULAR);
+    if error < 0.0 {
+        -rate
+    } else {
+        rate
+    }
+}
+
+/// Turns towards a target position, drives there while correcting the heading
+/// and optionally turns to a final heading. Positions in mm, angles in radians.
+pub struct GoTo {
+    x: f32,
+    y: f32,
+    theta: Option<f32>,
+    // m/s and m/s²
+    max_linear: f32,
+    min_linear: f32,
+    deceleration: f32,
+    phase: GoToPhase,
+}
+
+impl GoTo {
+    pub fn new(
+        x: f32,
+        y: f32,
+        theta: Option<f32>,
+        max_linear: f32,
+        min_linear: f32,
+        deceleration: f32,
+    ) -> GoTo {
+        GoTo {
+            x,
+            y,
+            theta,
+            max_linear,
+            min_linear: min_linear.min(max_linear),
+            deceleration,
+            phase: GoToPhase::Turn,
+        }
+    }
+
+    pub fn phase(&self) -> GoToPhase {
+        self.phase
+    }
+
+    pub fn distance(&self, pose: &Pose) -> f32 {
+        (self.x - pose.x).hypot(self.y - pose.y)
+    }
+
+    /// Linear (m/s) and angular (rad/s) velocity from the current pose, `None` once arrived.
+    pub fn step(&mut self, pose: &Pose) -> Option<(f32, f32)> {
+        let distance = self.distance(pose);
+        let bearing = normalize_angle((self.y - pose.y).atan2(self.x - pose.x) - pose.theta);
+
+        let travelling = self.phase == GoToPhase::Turn || self.phase == GoToPhase::Drive;
+        if travelling && distance <= POSITION_TOLERANCE_MM {
+            self.phase = match self.theta {
+                Some(_) => GoToPhase::Align,
+                None => GoToPhase::Arrived,
+            };
+        }
+
+        if self.phase == GoToPhase::Turn && bearing.abs() <= HEADING_TOLERANCE {
+            self.phase = GoToPhase::Drive;
+        } else if self.phase == GoToPhase::Drive && bearing.abs() > DRIVE_HEADING {
+            self.phase = GoToPhase::Turn;
+        }
+
+        match self.phase {
+            GoToPhase::Turn => Some((0.0, turn_rate(bearing))),
+            GoToPhase::Drive => {
+                // v² = 2·a·s, slow enough to stop at the target
+                let stoppable = (2.0 * self.deceleration * distance / 1000.0).sqrt();
+                let linear = stoppable.min(self.max_linear).max(self.min_linear);
+                let angular = (HEADING_GAIN * bearing).max(-MAX_ANGULAR).min(MAX_ANGULAR);
+                Some((linear, angular))
+            }
+            GoToPhase::Align => {
+                let error = normalize_angle(self.theta.unwrap_or(pose.theta) - pose.theta);
+                if error.abs() <= HEADING_TOLERANCE {
+                    self.phase = GoToPhase::Arrived;
+                    None
+                } else {
+                    Some((0.0, turn_rate(error)))
+                }
+            }
+            GoToPhase::Arrived => None,
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use futures::sync::mpsc;
+
+use tokio::prelude::*;
+
+use std::f32::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+use crate::command::OdometryCommand;
+use crate::config::Config;
+use crate::event::{EncodersSnapshot, Event, Pose, TimedEvent};
+use crate::geometry::Geometry;
+
+type Tx = mpsc::UnboundedSender<TimedEvent>;
+
+type RxCommand = mpsc::UnboundedReceiver<OdometryCommand>;
+type TxCommand = mpsc::UnboundedSender<OdometryCommand>;
+
+type RxEvent = mpsc::UnboundedReceiver<EncodersSnapshot>;
+type TxEvent = mpsc::UnboundedSender<EncodersSnapshot>;
+
+// Without encoder snapshots for this long the rover is taken to be standing still
+const STALE_MS: u64 = 1000;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct OdometryConfig {
+    // How often the `Odometry` event is published
+    pub publish_ms: u64,
+}
+
+impl Default for OdometryConfig {
+    fn default() -> OdometryConfig {
+        OdometryConfig { publish_ms: 200 }
+    }
+}
+
+/// Wraps an angle into (-π, π].
+pub fn normalize_angle(angle: f32) -> f32 {
+    let mut angle = angle % (2.0 * PI);
+    if angle > PI {
+        angle -= 2.0 * PI;
+    } else if angle <= -PI {
+        angle += 2.0 * PI;
+    }
+    angle
+}
+
+struct OdometryState {
+    pose: Pose,
+    // mm/s and rad/s
+    linear: f32,
+    angular: f32,
+    last_update: Instant,
+    geometry: Geometry,
+}
+
+impl OdometryState {
+    // The counts are signed, so backward moves and turns need no commanded direction
+    fn update(&mut self, encoders: &EncodersSnapshot) {
+        let mm_per_tick = self.geometry.mm_per_tick();
+        let left = encoders.left as f32 * mm_per_tick;
+        let right = encoders.right as f32 * mm_per_tick;
+
+        let distance = (left + right) / 2.0;
+        let rotation = (right - left) / self.geometry.track_width_mm;
+
+        // Driving along the average heading of the interval follows arcs more closely
+        let heading = self.pose.theta + rotation / 2.0;
+        self.pose.x += distance * heading.cos();
+        self.pose.y += distance * heading.sin();
+        self.pose.theta = normalize_angle(self.pose.theta + rotation);
+
+        if encoders.duration > 0 {
+            let dt = encoders.duration as f32 / 1000.0;
+            self.linear = distance / dt;
+            self.angular = rotation / dt;
+        }
+        self.last_update = Instant::now();
+    }
+
+    fn event(&mut self) -> Event {
+        if self.last_update.elapsed() > Duration::from_millis(STALE_MS) {
+            self.linear = 0.0;
+            self.angular = 0.0;
+        }
+
+        Event::Odometry {
+            pose: self.pose,
+            linear: self.linear,
+            angular: self.angular,
+        }
+    }
+}
+
+/// Read-only view of the pose for other handlers.
+#[derive(Clone)]
+pub struct PoseHandle {
+    state: Arc<Mutex<OdometryState>>,
+}
+
+impl PoseHandle {
+    pub fn get(&self) -> Pose {
+        self.state.lock().unwrap().pose
+    }
+}
+
+/// Integrates the wheel encoders into a pose, x and y in mm and θ in radians
+/// counter-clockwise from where the rover started or the pose was last set.
+pub struct Odometry {
+    rx_command: RxCommand,
+    rx_event: RxEvent,
+    state: Arc<Mutex<OdometryState>>,
+    publish_ms: u64,
+    tx: Arc<Mutex<Tx>>,
+}
+
+impl Odometry {
+    pub fn new(tx: Arc<Mutex<Tx>>, config: &Config) -> (Odometry, TxCommand, TxEvent) {
+        let (tx_command, rx_command) = mpsc::unbounded();
+        let (tx_event, rx_event) = mpsc::unbounded();
+
+        let state = OdometryState {
+            pose: Pose::default(),
+            linear: 0.0,
+            angular: 0.0,
+            last_update: Instant::now(),
+            geometry: config.geometry.clone(),
+        };
+
+        (
+            Odometry {
+                rx_command,
+                rx_event,
+                state: Arc::new(Mutex::new(state)),
+                publish_ms: config.odometry.publish_ms,
+                tx,
+            },
+            tx_command,
+            tx_event,
+        )
+    }
+
+    pub fn pose_handle(&self) -> PoseHandle {
+        PoseHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        let state_command_arc = self.state.clone();
+        let command_handler = self
+            .rx_command
+            .for_each(move |command| {
+                let mut state = state_command_arc.lock().unwrap();
+                match command {
+                    OdometryCommand::ResetPose => {
+                        println!("Received odometry reset pose command ");
+                        state.pose = Pose::default();
+                    }
+                    OdometryCommand::SetPose { x, y, theta } => {
+                        println!("Received odometry set pose command ");
+                        state.pose = Pose {
+                            x,
+                            y,
+                            theta: normalize_angle(theta),
+                        };
+                    }
+                }
+
+                Ok(())
+            })
+            .map_err(|err| {
+                println!("odometry command error = {:?}", err);
+            });
+
+        let state_encoder_arc = self.state.clone();
+        let encoder_handler = self
+            .rx_event
+            .for_each(move |encoders| {
+                state_encoder_arc.lock().unwrap().update(&encoders);
+                Ok(())
+            })
+            .map_err(|err| {
+                println!("odometry encoder error = {:?}", err);
+            });
+
+        let state_publish_arc = self.state.clone();
+        let tx = self.tx;
+        let publisher = Interval::new(Instant::now(), Duration::from_millis(self.publish_ms))
+            .for_each(move |_| {
+                let event = state_publish_arc.lock().unwrap().event();
+                let tx = tx.lock().unwrap();
+                match tx.unbounded_send(TimedEvent::new(event)) {
+                    Ok(_) => (),
+                    Err(e) => println!("odometry send error = {:?}", e),
+                }
+                Ok(())
+            })
+            .map_err(|e| print!("interval errored; err={:?}", e));
+
+        command_handler
+            .join(encoder_handler)
+            .join(publisher)
+            .map(|_| ())
+    }
+}
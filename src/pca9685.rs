@@ -1,95 +1,418 @@
 use i2cdev::core::I2CDevice;
-pub use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
 use std::thread::sleep;
 use std::time::Duration;
 
+// Register-level stand-in for the chip, so the driver can be exercised without a Pi
+#[cfg(test)]
+mod mock;
+
 const MODE_1_REG: u8 = 0x00;
 const MODE_2_REG: u8 = 0x01;
 const LED0_ON_L: u8 = 0x06;
-const LED0_ON_H: u8 = 0x07;
-const LED0_OFF_L: u8 = 0x08;
-const LED0_OFF_H: u8 = 0x09;
+const ALL_LED_ON_L: u8 = 0xFA;
 const PRE_SCALE_REG: u8 = 0xFE;
 
+// MODE1
+const RESTART: u8 = 0b1 << 7;
 const AUTO_INCREMENT: u8 = 0b1 << 5;
+const SLEEP: u8 = 0b1 << 4;
+const ALL_CALL: u8 = 0b1;
+
+// MODE2
+// Only `set_inverted` uses it, which the rover doesn't need yet
+#[allow(dead_code)]
+const INVERT: u8 = 0b1 << 4;
+const OUTPUT_TOTEM_POLE: u8 = 0b1 << 2;
+
+// Bit 4 of LEDn_ON_H and LEDn_OFF_H, full off wins over full on
+const FULL: u16 = 0b1 << 12;
+
+pub const CHANNELS: u8 = 16;
+pub const MIN_FREQUENCY: u16 = 40;
+pub const MAX_FREQUENCY: u16 = 1000;
+const OSCILLATOR_HZ: f32 = 25_000_000.0;
+// The chip ignores lower prescale values
+const MIN_PRESCALE: f32 = 3.0;
+// Steps of one PWM period
+pub const STEPS: u16 = 4096;
 
-pub struct PCA9685 {
-    pub device: LinuxI2CDevice,
+// The oscillator needs 500µs to come back up after sleeping
+const OSCILLATOR_US: u64 = 500;
+
+/// Output of a channel, counted in `STEPS` of the PWM period.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    // Goes high at step `on` and low at step `off`
+    Pwm { on: u16, off: u16 },
+    FullOn,
+    FullOff,
 }
 
-impl PCA9685 {
-    pub fn new(device: LinuxI2CDevice, frequency: u16) -> Result<PCA9685, LinuxI2CError> {
-        assert!(frequency >= 40 && frequency <= 1000);
-
-        // Setting auto-increment lets us write independent PWM values to all
-        // channels using only one i2c write.
-        let mut mode1 = 0x01 | AUTO_INCREMENT;
-        let mut pca9685 = PCA9685 { device: device };
-
-        pca9685.device.smbus_write_byte_data(MODE_2_REG, 0x04)?;
-        pca9685.device.smbus_write_byte_data(MODE_1_REG, mode1)?;
-        sleep(Duration::from_millis(6));
-        mode1 &= !0x01;
-        pca9685.device.smbus_write_byte_data(MODE_1_REG, mode1)?;
-        sleep(Duration::from_millis(6));
-
-        // set frequency
-        let mut prescalelevel = 25000000.0;
-        prescalelevel /= 4096.0;
-        prescalelevel /= frequency as f32;
-        prescalelevel -= 1.0;
-
-        let mode = 0x01;
-        pca9685
-            .device
-            .smbus_write_byte_data(MODE_1_REG, (mode & 0x7F) | 0x10)?;
-        pca9685
-            .device
-            .smbus_write_byte_data(PRE_SCALE_REG, prescalelevel as u8)?;
-        pca9685.device.smbus_write_byte_data(MODE_1_REG, mode)?;
-        sleep(Duration::from_millis(6));
-        pca9685
-            .device
-            .smbus_write_byte_data(MODE_1_REG, mode | 0x80)?;
+impl Output {
+    /// High for the first `duty_cycle` steps of the period.
+    pub fn duty(duty_cycle: u16) -> Output {
+        assert!(duty_cycle < STEPS);
+        if duty_cycle == 0 {
+            Output::FullOff
+        } else {
+            Output::Pwm {
+                on: 0,
+                off: duty_cycle,
+            }
+        }
+    }
+
+    // LEDn_ON_L, LEDn_ON_H, LEDn_OFF_L, LEDn_OFF_H
+    fn registers(self) -> [u8; 4] {
+        let (on, off) = match self {
+            Output::Pwm { on, off } => (on & 0x0FFF, off & 0x0FFF),
+            Output::FullOn => (FULL, 0),
+            Output::FullOff => (0, FULL),
+        };
+        [
+            (on & 0xFF) as u8,
+            (on >> 8) as u8,
+            (off & 0xFF) as u8,
+            (off >> 8) as u8,
+        ]
+    }
+
+    fn from_registers(registers: &[u8]) -> Output {
+        let on = registers[0] as u16 | (registers[1] as u16) << 8;
+        let off = registers[2] as u16 | (registers[3] as u16) << 8;
+
+        if off & FULL != 0 {
+            Output::FullOff
+        } else if on & FULL != 0 {
+            Output::FullOn
+        } else {
+            Output::Pwm { on, off }
+        }
+    }
+}
+
+fn led_register(channel: u8) -> u8 {
+    assert!(channel < CHANNELS);
+    LED0_ON_L + 4 * channel
+}
+
+pub struct PCA9685<D: I2CDevice> {
+    pub device: D,
+    frequency: u16,
+}
+
+impl<D: I2CDevice> PCA9685<D> {
+    pub fn new(device: D, frequency: u16) -> Result<PCA9685<D>, D::Error> {
+        let mut pca9685 = PCA9685 {
+            device: device,
+            frequency,
+        };
+
+        pca9685.write_register(MODE_2_REG, OUTPUT_TOTEM_POLE)?;
+        // Setting auto-increment lets us write the PWM values of many channels in one i2c write.
+        // The prescaler can only be changed while sleeping.
+        pca9685.write_register(MODE_1_REG, AUTO_INCREMENT | ALL_CALL | SLEEP)?;
+        pca9685.set_frequency(frequency, 1.0)?;
+
+        // The chip keeps its outputs across a restart of the server, nothing should run until asked to
+        pca9685.set_all(Output::FullOff)?;
+        pca9685.wake()?;
 
         Ok(pca9685)
     }
 
-    pub fn set_duty_cycle(&mut self, channel: u8, duty_cycle: u16) -> Result<(), LinuxI2CError> {
-        assert!(duty_cycle < 4096);
-        // let off = 4095 - duty_cycle;
-        self.device
-            .smbus_write_byte_data(LED0_ON_L + 4 * channel, 0)?;
-        self.device
-            .smbus_write_byte_data(LED0_ON_H + 4 * channel, 0)?;
-        self.device
-            .smbus_write_byte_data(LED0_OFF_L + 4 * channel, (duty_cycle & 0xFF) as u8)?;
-        self.device
-            .smbus_write_byte_data(LED0_OFF_H + 4 * channel, (duty_cycle >> 8) as u8)?;
+    pub fn frequency(&self) -> u16 {
+        self.frequency
+    }
+
+    /// Sets the PWM frequency, `correction` scales the prescale for an oscillator which isn't quite 25MHz.
+    /// The chip sleeps while the prescaler changes and goes back to the mode it was in, returns the prescale.
+    pub fn set_frequency(&mut self, frequency: u16, correction: f32) -> Result<u8, D::Error> {
+        assert!(frequency >= MIN_FREQUENCY && frequency <= MAX_FREQUENCY);
+
+        let prescale = OSCILLATOR_HZ / STEPS as f32 / frequency as f32 - 1.0;
+        let prescale = (prescale * correction).round().max(MIN_PRESCALE).min(255.0) as u8;
+
+        let mode1 = self.read_register(MODE_1_REG)?;
+        self.write_register(MODE_1_REG, (mode1 & !RESTART) | SLEEP)?;
+        self.write_register(PRE_SCALE_REG, prescale)?;
+        self.write_register(MODE_1_REG, mode1 & !RESTART)?;
+
+        // Channels which were running carry on with the new frequency
+        if mode1 & SLEEP == 0 {
+            sleep(Duration::from_micros(OSCILLATOR_US));
+            self.write_register(MODE_1_REG, mode1 | RESTART)?;
+        }
+
+        self.frequency = frequency;
+        Ok(prescale)
+    }
+
+    pub fn read_register(&mut self, register: u8) -> Result<u8, D::Error> {
+        self.device.smbus_read_byte_data(register)
+    }
+
+    /// Reads consecutive registers starting at `register`.
+    pub fn read_registers(&mut self, register: u8, values: &mut [u8]) -> Result<(), D::Error> {
+        self.device.write(&[register])?;
+        self.device.read(values)
+    }
+
+    pub fn write_register(&mut self, register: u8, value: u8) -> Result<(), D::Error> {
+        self.device.smbus_write_byte_data(register, value)
+    }
+
+    /// Writes consecutive registers starting at `register` in one transaction.
+    pub fn write_registers(&mut self, register: u8, values: &[u8]) -> Result<(), D::Error> {
+        let mut data = Vec::with_capacity(values.len() + 1);
+        data.push(register);
+        data.extend_from_slice(values);
+        self.device.write(&data)
+    }
+
+    fn update_register(&mut self, register: u8, set: u8, clear: u8) -> Result<u8, D::Error> {
+        let value = (self.read_register(register)? & !clear) | set;
+        self.write_register(register, value)?;
+        Ok(value)
+    }
+
+    pub fn set_output(&mut self, channel: u8, output: Output) -> Result<(), D::Error> {
+        self.write_registers(led_register(channel), &output.registers())
+    }
+
+    /// Sets the channels from `first` onwards in one transaction.
+    pub fn set_outputs(&mut self, first: u8, outputs: &[Output]) -> Result<(), D::Error> {
+        assert!(first as usize + outputs.len() <= CHANNELS as usize);
+        let registers: Vec<u8> = outputs
+            .iter()
+            .flat_map(|output| output.registers().to_vec())
+            .collect();
+        self.write_registers(led_register(first), &registers)
+    }
+
+    /// Sets every channel through the ALL_LED registers, they can't be read back.
+    pub fn set_all(&mut self, output: Output) -> Result<(), D::Error> {
+        self.write_registers(ALL_LED_ON_L, &output.registers())
+    }
+
+    /// Reads back what a channel has been set to.
+    pub fn output(&mut self, channel: u8) -> Result<Output, D::Error> {
+        let mut registers = [0; 4];
+        self.read_registers(led_register(channel), &mut registers)?;
+        Ok(Output::from_registers(&registers))
+    }
+
+    pub fn set_duty_cycle(&mut self, channel: u8, duty_cycle: u16) -> Result<(), D::Error> {
+        self.set_output(channel, Output::duty(duty_cycle))
+    }
+
+    /// Sets the duty cycles of the channels from `first` onwards in one transaction.
+    pub fn set_duty_cycles(&mut self, first: u8, duty_cycles: &[u16]) -> Result<(), D::Error> {
+        let outputs: Vec<Output> = duty_cycles.iter().map(|&duty| Output::duty(duty)).collect();
+        self.set_outputs(first, &outputs)
+    }
+
+    /// Stops the oscillator, all outputs are off until `wake`.
+    #[allow(dead_code)]
+    pub fn sleep(&mut self) -> Result<(), D::Error> {
+        self.update_register(MODE_1_REG, SLEEP, 0)?;
+        Ok(())
+    }
+
+    /// Starts the oscillator and restarts the PWM channels where they were before sleeping.
+    pub fn wake(&mut self) -> Result<(), D::Error> {
+        let mode1 = self.update_register(MODE_1_REG, 0, SLEEP | RESTART)?;
+        sleep(Duration::from_micros(OSCILLATOR_US));
+
+        // Writing the RESTART bit back clears it and resumes the channels
+        if self.read_register(MODE_1_REG)? & RESTART != 0 {
+            self.write_register(MODE_1_REG, mode1 | RESTART)?;
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn is_sleeping(&mut self) -> Result<bool, D::Error> {
+        Ok(self.read_register(MODE_1_REG)? & SLEEP != 0)
+    }
+
+    /// Inverts the logic of all outputs, e.g. for LEDs driven from the supply.
+    #[allow(dead_code)]
+    pub fn set_inverted(&mut self, inverted: bool) -> Result<(), D::Error> {
+        if inverted {
+            self.update_register(MODE_2_REG, INVERT, 0)?;
+        } else {
+            self.update_register(MODE_2_REG, 0, INVERT)?;
+        }
         Ok(())
     }
 }
 
-/*
-
-    __MODE1 = 0x00,
-__PRESCALE = 0xFE,
-
-    pwm.setFreq = function (freq, correctionFactor) {
-        if (!isValidFreq(freq)) throw new Error("Frequency must be between 40 and 1000 Hz");
-        var oldmode, newmode, prescale, prescaleval;
-        correctionFactor = correctionFactor || 1.0;
-        prescaleval = 25000000;
-        prescaleval /= 4096.0;
-        prescaleval /= freq;
-        prescaleval -= 1.0;
-        prescale = Math.floor(prescaleval * correctionFactor + 0.5);
-        oldmode = i2cRead(this.i2c, __MODE1, 1);
-        newmode = (oldmode & 0x7F) | 0x10;
-        i2cSend(this.i2c, __MODE1, newmode);
-        i2cSend(this.i2c, __PRESCALE, Math.floor(prescale));
-        i2cSend(this.i2c, __MODE1, oldmode);
-        sleep.usleep(10000);
-        i2cSend(this.i2c, __MODE1, oldmode | 0x80);
+#[cfg(test)]
+mod tests {
+    use super::mock::MockPCA9685;
+    use super::*;
+
+    fn pca9685(frequency: u16) -> PCA9685<MockPCA9685> {
+        let mut pca9685 = PCA9685::new(MockPCA9685::new(), frequency).unwrap();
+        pca9685.device.writes.clear();
+        pca9685
+    }
+
+    // Register writes only, leaving out the register pointer writes of reads
+    fn register_writes(pca9685: &PCA9685<MockPCA9685>) -> Vec<Vec<u8>> {
+        pca9685
+            .device
+            .writes
+            .iter()
+            .filter(|write| write.len() > 1)
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn prescale() {
+        let mut pca9685 = pca9685(60);
+        assert_eq!(pca9685.device.register(PRE_SCALE_REG), 101);
+
+        assert_eq!(pca9685.set_frequency(50, 1.0).unwrap(), 121);
+        assert_eq!(pca9685.device.register(PRE_SCALE_REG), 121);
+
+        assert_eq!(pca9685.set_frequency(1000, 1.0).unwrap(), 5);
+        assert_eq!(pca9685.device.register(PRE_SCALE_REG), 5);
+        assert_eq!(pca9685.frequency(), 1000);
+    }
+
+    #[test]
+    fn prescale_correction() {
+        let mut pca9685 = pca9685(60);
+        assert_eq!(pca9685.set_frequency(50, 1.1).unwrap(), 133);
+        // Never below what the chip accepts
+        assert_eq!(pca9685.set_frequency(1000, 0.1).unwrap(), 3);
+    }
+
+    #[test]
+    fn output_registers() {
+        assert_eq!(Output::duty(0x0ABC).registers(), [0x00, 0x00, 0xBC, 0x0A]);
+        assert_eq!(
+            Output::Pwm {
+                on: 0x123,
+                off: 0xF456
+            }
+            .registers(),
+            [0x23, 0x01, 0x56, 0x04]
+        );
+        assert_eq!(Output::FullOn.registers(), [0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(Output::FullOff.registers(), [0x00, 0x00, 0x00, 0x10]);
+        assert_eq!(Output::duty(0), Output::FullOff);
+
+        // Full off wins when both bits are set
+        assert_eq!(Output::from_registers(&[0, 0x10, 0, 0x10]), Output::FullOff);
+        assert_eq!(Output::from_registers(&[0, 0x10, 0, 0]), Output::FullOn);
+    }
+
+    #[test]
+    fn led_registers() {
+        let mut pca9685 = pca9685(60);
+        pca9685.set_duty_cycle(3, 2048).unwrap();
+        assert_eq!(
+            register_writes(&pca9685),
+            vec![vec![0x12, 0x00, 0x00, 0x00, 0x08]]
+        );
+        assert_eq!(pca9685.output(3).unwrap(), Output::Pwm { on: 0, off: 2048 });
+
+        pca9685.device.writes.clear();
+        pca9685.set_duty_cycles(14, &[0, 4095]).unwrap();
+        assert_eq!(
+            register_writes(&pca9685),
+            vec![vec![0x3E, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0xFF, 0x0F]]
+        );
+        assert_eq!(pca9685.output(14).unwrap(), Output::FullOff);
+
+        pca9685.set_output(0, Output::FullOn).unwrap();
+        assert_eq!(pca9685.output(0).unwrap(), Output::FullOn);
+    }
+
+    #[test]
+    fn set_all() {
+        let mut pca9685 = pca9685(60);
+        pca9685.set_all(Output::FullOn).unwrap();
+        for channel in 0..CHANNELS {
+            assert_eq!(pca9685.output(channel).unwrap(), Output::FullOn);
+        }
+    }
+
+    #[test]
+    fn new_starts_awake_and_off() {
+        let mut pca9685 = pca9685(60);
+        assert_eq!(
+            pca9685.device.register(MODE_1_REG),
+            AUTO_INCREMENT | ALL_CALL
+        );
+        assert_eq!(pca9685.device.register(MODE_2_REG), OUTPUT_TOTEM_POLE);
+        assert_eq!(pca9685.output(5).unwrap(), Output::FullOff);
+    }
+
+    #[test]
+    fn frequency_restarts_running_channels() {
+        let mut pca9685 = pca9685(60);
+        pca9685.set_frequency(1000, 1.0).unwrap();
+
+        let awake = AUTO_INCREMENT | ALL_CALL;
+        assert_eq!(
+            register_writes(&pca9685),
+            vec![
+                vec![MODE_1_REG, awake | SLEEP],
+                vec![PRE_SCALE_REG, 5],
+                vec![MODE_1_REG, awake],
+                vec![MODE_1_REG, awake | RESTART],
+            ]
+        );
+        assert_eq!(pca9685.device.register(MODE_1_REG), awake);
+    }
+
+    #[test]
+    fn frequency_keeps_sleeping() {
+        let mut pca9685 = pca9685(60);
+        pca9685.sleep().unwrap();
+        pca9685.device.writes.clear();
+        pca9685.set_frequency(50, 1.0).unwrap();
+
+        let asleep = AUTO_INCREMENT | ALL_CALL | SLEEP;
+        assert_eq!(
+            register_writes(&pca9685),
+            vec![
+                vec![MODE_1_REG, asleep],
+                vec![PRE_SCALE_REG, 121],
+                vec![MODE_1_REG, asleep],
+            ]
+        );
+        assert!(pca9685.is_sleeping().unwrap());
+        assert_eq!(pca9685.device.register(PRE_SCALE_REG), 121);
+    }
+
+    #[test]
+    fn sleep_and_wake() {
+        let mut pca9685 = pca9685(60);
+        pca9685.sleep().unwrap();
+        assert!(pca9685.is_sleeping().unwrap());
+        assert_ne!(pca9685.device.register(MODE_1_REG) & RESTART, 0);
+
+        pca9685.wake().unwrap();
+        assert!(!pca9685.is_sleeping().unwrap());
+        assert_eq!(pca9685.device.register(MODE_1_REG) & RESTART, 0);
+    }
+
+    #[test]
+    fn inverted() {
+        let mut pca9685 = pca9685(60);
+        pca9685.set_inverted(true).unwrap();
+        assert_eq!(
+            pca9685.device.register(MODE_2_REG),
+            OUTPUT_TOTEM_POLE | INVERT
+        );
+        pca9685.set_inverted(false).unwrap();
+        assert_eq!(pca9685.device.register(MODE_2_REG), OUTPUT_TOTEM_POLE);
+    }
 }
-*/
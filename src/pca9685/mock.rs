@@ -0,0 +1,154 @@
+use i2cdev::core::I2CDevice;
+use std::io;
+
+use super::{
+    ALL_CALL, ALL_LED_ON_L, AUTO_INCREMENT, CHANNELS, LED0_ON_L, MODE_1_REG, MODE_2_REG,
+    OUTPUT_TOTEM_POLE, PRE_SCALE_REG, RESTART, SLEEP,
+};
+
+const REGISTERS: usize = 256;
+const ALL_LED_OFF_H: u8 = ALL_LED_ON_L + 3;
+
+/// Keeps the registers of a PCA9685 and follows the parts of the datasheet the driver relies on:
+/// auto-increment, the write-only ALL_LED registers, the prescaler only being writable
+/// while sleeping and the RESTART bit being set by sleeping and cleared by writing a one.
+pub struct MockPCA9685 {
+    pub registers: [u8; REGISTERS],
+    // Every I2C write as sent, register address first
+    pub writes: Vec<Vec<u8>>,
+    pointer: u8,
+}
+
+impl MockPCA9685 {
+    /// Registers as they are after power-on.
+    pub fn new() -> MockPCA9685 {
+        let mut registers = [0; REGISTERS];
+        registers[MODE_1_REG as usize] = SLEEP | ALL_CALL;
+        registers[MODE_2_REG as usize] = OUTPUT_TOTEM_POLE;
+        registers[PRE_SCALE_REG as usize] = 0x1E;
+        // All channels start full off
+        for channel in 0..CHANNELS {
+            registers[(LED0_ON_L + 4 * channel) as usize + 3] = 0x10;
+        }
+
+        MockPCA9685 {
+            registers,
+            writes: Vec::new(),
+            pointer: 0,
+        }
+    }
+
+    pub fn register(&self, register: u8) -> u8 {
+        self.registers[register as usize]
+    }
+
+    fn is_sleeping(&self) -> bool {
+        self.register(MODE_1_REG) & SLEEP != 0
+    }
+
+    fn store(&mut self, register: u8, value: u8) {
+        match register {
+            MODE_1_REG => {
+                let old = self.register(MODE_1_REG);
+                // Writing a one clears RESTART, writing a zero leaves it alone
+                let restart = if value & RESTART != 0 {
+                    0
+                } else {
+                    old & RESTART
+                };
+                // Going to sleep allows restarting the channels on waking up
+                let slept = if old & SLEEP == 0 && value & SLEEP != 0 {
+                    RESTART
+                } else {
+                    0
+                };
+                self.registers[MODE_1_REG as usize] = (value & !RESTART) | restart | slept;
+            }
+            PRE_SCALE_REG => {
+                if self.is_sleeping() {
+                    self.registers[PRE_SCALE_REG as usize] = value;
+                }
+            }
+            ALL_LED_ON_L..=ALL_LED_OFF_H => {
+                let offset = register - ALL_LED_ON_L;
+                for channel in 0..CHANNELS {
+                    self.registers[(LED0_ON_L + 4 * channel + offset) as usize] = value;
+                }
+            }
+            _ => self.registers[register as usize] = value,
+        }
+    }
+
+    fn load(&self, register: u8) -> u8 {
+        match register {
+            // Write only
+            ALL_LED_ON_L..=ALL_LED_OFF_H => 0,
+            _ => self.register(register),
+        }
+    }
+
+    fn advance(&mut self) {
+        if self.register(MODE_1_REG) & AUTO_INCREMENT != 0 {
+            self.pointer = self.pointer.wrapping_add(1);
+        }
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "the PCA9685 doesn't support SMBus blocks",
+    )
+}
+
+impl I2CDevice for MockPCA9685 {
+    type Error = io::Error;
+
+    fn read(&mut self, data: &mut [u8]) -> io::Result<()> {
+        for byte in data.iter_mut() {
+            *byte = self.load(self.pointer);
+            self.advance();
+        }
+        Ok(())
+    }
+
+    // The first byte selects the register, the rest is written from there on
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writes.push(data.to_vec());
+
+        let (&register, values) = match data.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        self.pointer = register;
+        for &value in values {
+            let pointer = self.pointer;
+            self.store(pointer, value);
+            self.advance();
+        }
+        Ok(())
+    }
+
+    fn smbus_write_quick(&mut self, _bit: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn smbus_read_block_data(&mut self, _register: u8) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+
+    fn smbus_read_i2c_block_data(&mut self, register: u8, len: u8) -> io::Result<Vec<u8>> {
+        self.pointer = register;
+        let mut data = vec![0; len as usize];
+        self.read(&mut data)?;
+        Ok(data)
+    }
+
+    fn smbus_write_block_data(&mut self, _register: u8, _values: &[u8]) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn smbus_process_block(&mut self, _register: u8, _values: &[u8]) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,30 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Balena keeps /data between container restarts, anything the rover has to remember lives there
+const DATA_DIR: &str = "/data";
+
+/// Path of a file or directory in the persistent data directory.
+pub fn data_path(name: &str) -> PathBuf {
+    Path::new(DATA_DIR).join(name)
+}
+
+/// Reads a JSON file, a file which can't be parsed is an `InvalidData` error.
+pub fn load_json<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> io::Result<T> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// Writes a JSON file, creating the directories it is in.
+pub fn save_json<T: Serialize, P: AsRef<Path>>(path: P, value: &T) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = File::create(path)?;
+    serde_json::to_writer_pretty(file, value)?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use crate::geometry::Geometry;
+
+/// Acceleration limits for every move, in mm/s² and mm/s.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MotionLimits {
+    pub acceleration: f32,
+    pub deceleration: f32,
+    // Slowest speed at which the rover still moves, a profile never plans below it
+    pub min_speed: f32,
+}
+
+impl Default for MotionLimits {
+    fn default() -> MotionLimits {
+        MotionLimits {
+            acceleration: 300.0,
+            deceleration: 300.0,
+            min_speed: 40.0,
+        }
+    }
+}
+
+/// The same limits in encoder ticks.
+#[derive(Clone, Copy)]
+pub struct TickLimits {
+    pub acceleration: f32,
+    pub deceleration: f32,
+    pub min_speed: f32,
+}
+
+impl TickLimits {
+    pub fn new(limits: &MotionLimits, geometry: &Geometry) -> TickLimits {
+        let mm_per_tick = geometry.mm_per_tick();
+        TickLimits {
+            acceleration: limits.acceleration / mm_per_tick,
+            deceleration: limits.deceleration / mm_per_tick,
+            min_speed: limits.min_speed / mm_per_tick,
+        }
+    }
+}
+
+/// Trapezoidal velocity profile over a fixed distance: accelerate up to the cruise speed
+/// and start braking early enough to arrive at the minimum speed.
+pub struct Profile {
+    cruise: f32,
+    limits: TickLimits,
+    speed: f32,
+}
+
+impl Profile {
+    pub fn new(cruise: f32, limits: TickLimits) -> Profile {
+        Profile {
+            cruise,
+            limits,
+            speed: limits.min_speed.min(cruise),
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Speed for the next interval of `dt` seconds with `remaining` ticks to go.
+    pub fn next(&mut self, dt: f32, remaining: f32) -> f32 {
+        let accelerated = self.speed + self.limits.acceleration * dt;
+        // v² = 2·a·s, the fastest speed which still stops within the remaining distance
+        let stoppable = (2.0 * self.limits.deceleration * remaining.max(0.0)).sqrt();
+
+        self.speed = accelerated
+            .min(self.cruise)
+            .min(stoppable)
+            .max(self.limits.min_speed.min(self.cruise));
+        self.speed
+    }
+}
+
+/// Moves `current` towards `target` without exceeding the acceleration limits.
+pub fn ramp(current: f32, target: f32, limits: TickLimits, dt: f32) -> f32 {
+    if target > current {
+        target.min(current + limits.acceleration * dt)
+    } else {
+        target.max(current - limits.deceleration * dt)
+    }
+}
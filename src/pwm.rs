@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+
+use crate::pca9685::{Output, CHANNELS, MAX_FREQUENCY, MIN_FREQUENCY, PCA9685};
+
+pub const DEFAULT_I2C_BUS: &str = "/dev/i2c-1";
+const DEFAULT_BOARD: &str = "main";
+pub const LEFT_MOTOR: &str = "left_motor";
+pub const RIGHT_MOTOR: &str = "right_motor";
+
+/// A PCA9685 on an I2C bus.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct BoardConfig {
+    pub bus: String,
+    pub address: u16,
+    pub frequency: u16,
+}
+
+impl Default for BoardConfig {
+    fn default() -> BoardConfig {
+        BoardConfig {
+            bus: DEFAULT_I2C_BUS.to_string(),
+            address: 0x40,
+            frequency: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct ChannelConfig {
+    pub board: String,
+    pub channel: u8,
+}
+
+/// Boards by name and the logical channels on them, by default one board driving both motors.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PwmConfig {
+    pub boards: BTreeMap<String, BoardConfig>,
+    pub channels: BTreeMap<String, ChannelConfig>,
+}
+
+impl Default for PwmConfig {
+    fn default() -> PwmConfig {
+        let mut boards = BTreeMap::new();
+        boards.insert(DEFAULT_BOARD.to_string(), BoardConfig::default());
+
+        let mut channels = BTreeMap::new();
+        for (index, name) in [LEFT_MOTOR, RIGHT_MOTOR].iter().enumerate() {
+            channels.insert(
+                name.to_string(),
+                ChannelConfig {
+                    board: DEFAULT_BOARD.to_string(),
+                    channel: index as u8,
+                },
+            );
+        }
+
+        PwmConfig { boards, channels }
+    }
+}
+
+#[derive(Debug)]
+pub enum PwmError {
+    UnknownChannel(String),
+    // The board of the channel couldn't be opened
+    NoBoard(String),
+    I2C(LinuxI2CError),
+}
+
+impl fmt::Display for PwmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PwmError::UnknownChannel(name) => write!(f, "no PWM channel '{}'", name),
+            PwmError::NoBoard(name) => write!(f, "PWM board '{}' isn't available", name),
+            PwmError::I2C(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<LinuxI2CError> for PwmError {
+    fn from(e: LinuxI2CError) -> PwmError {
+        PwmError::I2C(e)
+    }
+}
+
+fn open_board(board: &BoardConfig) -> Result<PCA9685<LinuxI2CDevice>, LinuxI2CError> {
+    let device = LinuxI2CDevice::new(&board.bus, board.address)?;
+    PCA9685::new(device, board.frequency)
+}
+
+/// Owns the PCA9685 boards and addresses their outputs by logical channel names.
+pub struct Pwm {
+    boards: BTreeMap<String, PCA9685<LinuxI2CDevice>>,
+    channels: BTreeMap<String, ChannelConfig>,
+}
+
+/// The boards are shared between the motors and the servos.
+pub type SharedPwm = Arc<Mutex<Pwm>>;
+
+impl Pwm {
+    // Boards which can't be opened and channels which don't fit are left out
+    pub fn open(config: &PwmConfig) -> Pwm {
+        let mut boards = BTreeMap::new();
+        for (name, board) in &config.boards {
+            if board.frequency < MIN_FREQUENCY || board.frequency > MAX_FREQUENCY {
+                println!(
+                    "PWM board '{}' frequency has to be between {} and {} Hz",
+                    name, MIN_FREQUENCY, MAX_FREQUENCY
+                );
+                continue;
+            }
+            match open_board(board) {
+                Ok(pca) => {
+                    boards.insert(name.clone(), pca);
+                }
+                Err(e) => println!("Error opening PWM board '{}' {:?}", name, e),
+            }
+        }
+
+        let mut channels: BTreeMap<String, ChannelConfig> = BTreeMap::new();
+        for (name, channel) in &config.channels {
+            if !config.boards.contains_key(&channel.board) || channel.channel >= CHANNELS {
+                println!(
+                    "PWM channel '{}' has no channel {} on board '{}'",
+                    name, channel.channel, channel.board
+                );
+                continue;
+            }
+            if let Some((other, _)) = channels.iter().find(|(_, other)| *other == channel) {
+                println!("PWM channel '{}' is already used by '{}'", name, other);
+                continue;
+            }
+            channels.insert(name.clone(), channel.clone());
+        }
+
+        Pwm { boards, channels }
+    }
+
+    pub fn shared(self) -> SharedPwm {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Whether the channel is configured and its board is working.
+    pub fn has_channel(&self, name: &str) -> bool {
+        self.channels
+            .get(name)
+            .map_or(false, |channel| self.boards.contains_key(&channel.board))
+    }
+
+    /// Whether both channels are configured on the same board.
+    pub fn same_board(&self, a: &str, b: &str) -> bool {
+        match (self.channels.get(a), self.channels.get(b)) {
+            (Some(a), Some(b)) => a.board == b.board,
+            _ => false,
+        }
+    }
+
+    fn channel(&self, name: &str) -> Result<ChannelConfig, PwmError> {
+        self.channels
+            .get(name)
+            .cloned()
+            .ok_or_else(|| PwmError::UnknownChannel(name.to_string()))
+    }
+
+    fn board(&mut self, board: &str) -> Result<&mut PCA9685<LinuxI2CDevice>, PwmError> {
+        self.boards
+            .get_mut(board)
+            .ok_or_else(|| PwmError::NoBoard(board.to_string()))
+    }
+
+    pub fn frequency(&mut self, name: &str) -> Result<u16, PwmError> {
+        let channel = self.channel(name)?;
+        Ok(self.board(&channel.board)?.frequency())
+    }
+
+    pub fn set_duty_cycle(&mut self, name: &str, duty_cycle: u16) -> Result<(), PwmError> {
+        let channel = self.channel(name)?;
+        self.board(&channel.board)?
+            .set_duty_cycle(channel.channel, duty_cycle)?;
+        Ok(())
+    }
+
+    /// Channels next to each other on one board are set in one transaction.
+    pub fn set_duty_cycles(&mut self, names: &[&str], duty_cycles: &[u16]) -> Result<(), PwmError> {
+        let channels = names
+            .iter()
+            .map(|name| self.channel(name))
+            .collect::<Result<Vec<ChannelConfig>, PwmError>>()?;
+
+        let consecutive = channels
+            .windows(2)
+            .all(|pair| pair[1].board == pair[0].board && pair[1].channel == pair[0].channel + 1);
+        match channels.first() {
+            Some(first) if consecutive => {
+                self.board(&first.board)?
+                    .set_duty_cycles(first.channel, duty_cycles)?;
+            }
+            _ => {
+                for (channel, &duty_cycle) in channels.iter().zip(duty_cycles) {
+                    self.board(&channel.board)?
+                        .set_duty_cycle(channel.channel, duty_cycle)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets any channel of a board, whatever it is mapped to, and reads it back.
+    pub fn set_raw(
+        &mut self,
+        board: &str,
+        channel: u8,
+        duty_cycle: u16,
+    ) -> Result<Output, PwmError> {
+        let pca = self.board(board)?;
+        pca.set_duty_cycle(channel, duty_cycle)?;
+        Ok(pca.output(channel)?)
+    }
+
+    /// Sets the frequency of the boards of the channels, returns the prescale of the last one.
+    pub fn set_frequency(
+        &mut self,
+        names: &[&str],
+        frequency: u16,
+        correction: f32,
+    ) -> Result<u8, PwmError> {
+        let mut boards: Vec<String> = Vec::new();
+        for name in names {
+            let board = self.channel(name)?.board;
+            if !boards.contains(&board) {
+                boards.push(board);
+            }
+        }
+
+        let mut prescale = 0;
+        for board in &boards {
+            prescale = self.board(board)?.set_frequency(frequency, correction)?;
+        }
+        Ok(prescale)
+    }
+}
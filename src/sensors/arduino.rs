@@ -61,15 +61,6 @@ fn parse_float(n: &str) -> Result<f32, io::Error> {
     })
 }
 
-fn parse_u8(n: &str) -> Result<u8, io::Error> {
-    n.parse().map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Could not parse number '{}', {}", n, e),
-        )
-    })
-}
-
 fn parse_isize(n: &str) -> Result<isize, io::Error> {
     n.parse().map_err(|e| {
         io::Error::new(
@@ -109,9 +100,11 @@ fn parse_encoders(encoders: &str) -> Result<ArduinoEvent, io::Error> {
     match encoders.split(",").collect::<Vec<&str>>().as_slice() {
         [left, right, duration] => Ok(ArduinoEvent::Encoders {
             encoders: EncodersSnapshot {
-                left: parse_u8(left)?,
-                right: parse_u8(right)?,
+                left: parse_isize(left)?,
+                right: parse_isize(right)?,
                 duration: parse_isize(duration)?,
+                left_total: 0,
+                right_total: 0,
             },
         }),
         _ => Err(io::Error::new(
@@ -179,10 +172,20 @@ impl Arduino {
             });
 
         let tx_arc = self.tx.clone();
+        let mut totals: (isize, isize) = (0, 0);
         let messages = reader
             .for_each(move |s| {
                 let s_tx = tx_arc.lock().unwrap();
                 let send_result = decode_event(s.freeze())
+                    .map(|mut event| {
+                        if let ArduinoEvent::Encoders { ref mut encoders } = event {
+                            totals.0 += encoders.left;
+                            totals.1 += encoders.right;
+                            encoders.left_total = totals.0;
+                            encoders.right_total = totals.1;
+                        }
+                        event
+                    })
                     .map(|event| Event::Arduino { event })
                     .and_then(|event| {
                         s_tx.unbounded_send(TimedEvent::new(event))
@@ -8,22 +8,46 @@ use crate::event::{EncoderEvent, Event, TimedEvent, Wheel};
 
 type Tx = mpsc::UnboundedSender<TimedEvent>;
 
+// Channel A and B pins
+const LEFT_PINS: (u64, u64) = (23, 24);
+const RIGHT_PINS: (u64, u64) = (22, 25);
+
 pub struct Encoder {
     tx: Arc<Mutex<Tx>>,
 }
 
-fn port_listen(pin_number: u64, wheel: Wheel, tx: Arc<Mutex<Tx>>) -> sysfs_gpio::Result<()> {
-    let input = Pin::new(pin_number);
+// Both edges of channel A are decoded (x2), the level of B at the edge gives the direction.
+// The right motor is mirrored, so its channels are swapped relative to the rover's forward.
+fn port_listen(
+    pins: (u64, u64),
+    wheel: Wheel,
+    reversed: bool,
+    tx: Arc<Mutex<Tx>>,
+) -> sysfs_gpio::Result<()> {
+    let (a_pin_number, b_pin_number) = pins;
+    let input_a = Pin::new(a_pin_number);
+    let input_b = Pin::new(b_pin_number);
+
+    input_b.export()?;
+    input_b.set_direction(Direction::In)?;
 
-    input.with_exported(|| {
-        input.set_direction(Direction::In)?;
-        input.set_edge(Edge::RisingEdge)?;
-        let mut poller = input.get_poller()?;
+    input_a.with_exported(|| {
+        input_a.set_direction(Direction::In)?;
+        input_a.set_edge(Edge::BothEdges)?;
+        let mut poller = input_a.get_poller()?;
+        let mut total: isize = 0;
         loop {
             match poller.poll(1000)? {
-                Some(_val) => {
+                Some(a_value) => {
+                    let b_value = input_b.get_value()?;
+                    let forward = (a_value != b_value) != reversed;
+                    let delta = if forward { 1 } else { -1 };
+                    total += delta;
+
                     let encoder_event = EncoderEvent {
                         wheel: wheel.clone(),
+                        delta,
+                        total,
                     };
                     let event = Event::Encoder {
                         event: encoder_event,
@@ -48,16 +72,20 @@ impl Encoder {
 
     pub fn run(self) -> () {
         let left_tx = self.tx.clone();
-        thread::spawn(move || match port_listen(23, Wheel::Left, left_tx) {
-            Ok(_) => (),
-            Err(e) => println!("Interrupt failed on pin {} {}", 23, e),
-        });
+        thread::spawn(
+            move || match port_listen(LEFT_PINS, Wheel::Left, false, left_tx) {
+                Ok(_) => (),
+                Err(e) => println!("Interrupt failed on pins {:?} {}", LEFT_PINS, e),
+            },
+        );
 
         let right_tx = self.tx.clone();
-        thread::spawn(move || match port_listen(22, Wheel::Right, right_tx) {
-            Ok(_) => (),
-            Err(e) => println!("Interrupt failed on pin {} {}", 22, e),
-        });
+        thread::spawn(
+            move || match port_listen(RIGHT_PINS, Wheel::Right, true, right_tx) {
+                Ok(_) => (),
+                Err(e) => println!("Interrupt failed on pins {:?} {}", RIGHT_PINS, e),
+            },
+        );
 
         ()
     }
@@ -1,6 +1,14 @@
 #![allow(unused)]
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::calibration::WheelCurve;
+use crate::command::Direction;
+use crate::gains::Pid;
+use crate::motor_handler::MotionMode;
+use crate::pca9685::Output;
+
 #[derive(Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum Wheel {
@@ -8,12 +16,16 @@ pub enum Wheel {
     Right,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub struct EncodersSnapshot {
-    pub left: u8,
-    pub right: u8,
+    // Signed counts since the previous snapshot, negative when turning backward
+    pub left: isize,
+    pub right: isize,
     pub duration: isize,
+    // Signed counts since the server started
+    pub left_total: isize,
+    pub right_total: isize,
 }
 
 #[derive(Serialize)]
@@ -28,22 +40,143 @@ pub enum ArduinoEvent {
 #[serde(rename_all = "lowercase")]
 pub struct EncoderEvent {
     pub wheel: Wheel,
+    // +1 forward, -1 backward
+    pub delta: isize,
+    pub total: isize,
 }
 
 #[derive(Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
-pub struct MotorRunStat {
-    pub speed_base: f32,
-    pub speed_slave: f32,
-    pub ticks_base: isize,
-    pub ticks_slave: isize,
+pub struct WheelRunStat {
+    pub target: f32,
+    pub speed: f32,
+    pub ticks: isize,
     pub error: f32,
     pub p_term: f32,
     pub i_term: f32,
     pub d_term: f32,
+    pub output: f32,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct MotorRunStat {
+    pub left: WheelRunStat,
+    pub right: WheelRunStat,
+    pub coupling_error: f32,
+    pub coupling_term: f32,
     pub duration: isize,
 }
 
+#[derive(Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    pub theta: f32,
+}
+
+// Answer to a `DebugCommand`
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DebugResult {
+    Pwm {
+        board: String,
+        channel: u8,
+        output: Output,
+    },
+    Gpio {
+        pin: u64,
+        value: u8,
+    },
+    I2c {
+        bus: String,
+        address: u16,
+        register: u8,
+        value: u8,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct ServoStatus {
+    pub channel: String,
+    // Degrees, `None` while the servo isn't powered
+    pub angle: Option<f32>,
+    // Where a running sweep is heading
+    pub target: Option<f32>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GoToPhase {
+    // Turning on the spot towards the target
+    Turn,
+    Drive,
+    // Turning to the final heading
+    Align,
+    Arrived,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RunEnd {
+    Completed,
+    Stopped,
+    // Replaced by another motion command
+    Replaced,
+    Heartbeat,
+    Fault,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FaultCause {
+    // Driven above the stall threshold without any ticks
+    Stall,
+    // One wheel fell behind the other
+    Disagreement,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct MotorFault {
+    pub wheel: Wheel,
+    pub cause: FaultCause,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MotionStatus {
+    Queued,
+    Started,
+    Finished,
+    Aborted,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct MotorStatus {
+    pub is_moving: bool,
+    pub mode: MotionMode,
+    pub direction: Direction,
+    pub speed: u8,
+    pub ticks_moved: isize,
+    pub ticks_remaining: isize,
+    pub linear: f32,
+    pub angular: f32,
+    pub current: Option<u32>,
+    pub queued: usize,
+    pub paused: bool,
+    pub profile: String,
+    pub fault: Option<MotorFault>,
+    // Name of the trajectory being recorded
+    pub recording: Option<String>,
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+}
+
 #[derive(Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub struct LidarScanPoint {
@@ -54,6 +187,36 @@ pub struct LidarScanPoint {
     pub is_valid: bool
 }
 
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Ws,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisconnectReason {
+    Closed,
+    Timeout,
+    Kicked,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct ClientInfo {
+    pub id: u32,
+    pub addr: String,
+    pub transport: Transport,
+    pub name: Option<String>,
+    pub user_agent: Option<String>,
+    pub connected_at: u128,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Event {
@@ -63,11 +226,59 @@ pub enum Event {
     Arduino {
         event: ArduinoEvent,
     },
+    MotorRunStat {
+        stat: MotorRunStat,
+    },
     MotorRunStats {
         stats: Vec<MotorRunStat>,
+        end: RunEnd,
+        p: f32,
+        i: f32,
+        d: f32,
+    },
+    AutoTune {
         p: f32,
         i: f32,
         d: f32,
+        ultimate_gain: f32,
+        ultimate_period: f32,
+        saved: bool,
+    },
+    MotorFault {
+        fault: MotorFault,
+    },
+    GoTo {
+        phase: GoToPhase,
+        // mm left to the target position
+        distance: f32,
+    },
+    Odometry {
+        pose: Pose,
+        linear: f32,
+        angular: f32,
+    },
+    Calibration {
+        left: Option<WheelCurve>,
+        right: Option<WheelCurve>,
+    },
+    PidProfiles {
+        active: String,
+        profiles: BTreeMap<String, Pid>,
+    },
+    Servos {
+        servos: BTreeMap<String, ServoStatus>,
+    },
+    Trajectory {
+        name: String,
+        segments: usize,
+    },
+    PwmFrequency {
+        frequency: u16,
+        correction: f32,
+        prescale: u8,
+    },
+    Debug {
+        result: DebugResult,
     },
     Lidar {
         scan_points: Vec<LidarScanPoint>,
@@ -75,6 +286,71 @@ pub enum Event {
     Generic {
         message: String,
     },
+    ClientConnected {
+        client: ClientInfo,
+    },
+    ClientDisconnected {
+        client: ClientInfo,
+        reason: DisconnectReason,
+    },
+    Clients {
+        clients: Vec<ClientInfo>,
+    },
+    Error {
+        message: String,
+    },
+    State {
+        events: BTreeMap<&'static str, Value>,
+        motor: MotorStatus,
+    },
+    MotorStatus {
+        status: MotorStatus,
+    },
+    Motion {
+        id: u32,
+        status: MotionStatus,
+    },
+}
+
+impl Event {
+    /// Name under which the latest event of this kind is cached,
+    /// `None` for events which don't describe the rover's state.
+    pub fn topic(&self) -> Option<&'static str> {
+        match self {
+            Event::Encoder { event } => match event.wheel {
+                Wheel::Left => Some("encoder_left"),
+                Wheel::Right => Some("encoder_right"),
+            },
+            Event::Arduino { event } => match event {
+                ArduinoEvent::Power { .. } => Some("power"),
+                ArduinoEvent::Temp { .. } => Some("temp"),
+                ArduinoEvent::Encoders { .. } => Some("encoders"),
+            },
+            Event::MotorRunStats { .. } => Some("motorrunstats"),
+            Event::AutoTune { .. } => Some("autotune"),
+            Event::PidProfiles { .. } => Some("pidprofiles"),
+            Event::Calibration { .. } => Some("calibration"),
+            Event::Odometry { .. } => Some("odometry"),
+            Event::Servos { .. } => Some("servos"),
+            Event::PwmFrequency { .. } => Some("pwmfrequency"),
+            Event::Lidar { .. } => Some("lidar"),
+            Event::Generic { .. } => Some("generic"),
+            // Progress of the motors, the motor status carries their current state
+            Event::MotorRunStat { .. }
+            | Event::MotorFault { .. }
+            | Event::GoTo { .. }
+            | Event::Motion { .. } => None,
+            // Answers and notifications for single clients or moments
+            Event::Trajectory { .. }
+            | Event::Debug { .. }
+            | Event::ClientConnected { .. }
+            | Event::ClientDisconnected { .. }
+            | Event::Clients { .. }
+            | Event::Error { .. }
+            | Event::State { .. }
+            | Event::MotorStatus { .. } => None,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -84,16 +360,20 @@ pub struct TimedEvent {
     pub time: u128,
 }
 
+pub fn get_millis() -> u128 {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    since_the_epoch.as_secs() as u128 * 1000 + since_the_epoch.subsec_millis() as u128
+}
+
 impl TimedEvent {
     #[repr(u128)]
     pub fn new(event: Event) -> TimedEvent {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        let time =
-            since_the_epoch.as_secs() as u128 * 1000 + since_the_epoch.subsec_millis() as u128;
-
-        TimedEvent { event, time }
+        TimedEvent {
+            event,
+            time: get_millis(),
+        }
     }
 }
@@ -0,0 +1,353 @@
+use futures::sync::mpsc;
+
+use tokio::prelude::*;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+use crate::command::ServoCommand;
+use crate::config::Config;
+use crate::event::{Event, ServoStatus, TimedEvent};
+use crate::motor::MOTOR_CHANNELS;
+use crate::pca9685::STEPS;
+use crate::pwm::SharedPwm;
+
+type Tx = mpsc::UnboundedSender<TimedEvent>;
+
+type RxCommand = mpsc::UnboundedReceiver<ServoCommand>;
+type TxCommand = mpsc::UnboundedSender<ServoCommand>;
+
+// Sweeps move the servos in steps of this period, about one pulse of a 50-60Hz servo
+const STEP_MS: u64 = 20;
+
+/// A hobby servo on a PWM channel, angles in degrees.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ServoConfig {
+    // Logical PWM channel, the name of the servo when not given
+    pub channel: Option<String>,
+    // Pulse widths at `min_angle` and `max_angle`, the servo is never driven past them
+    pub min_pulse_us: f32,
+    pub max_pulse_us: f32,
+    pub min_angle: f32,
+    pub max_angle: f32,
+    // Fastest sweep in degrees per second, also the sweep speed when none is given
+    pub max_speed: f32,
+    // Angle the servo is moved to on startup, left unpowered when not set
+    pub initial: Option<f32>,
+}
+
+impl Default for ServoConfig {
+    fn default() -> ServoConfig {
+        ServoConfig {
+            channel: None,
+            min_pulse_us: 1000.0,
+            max_pulse_us: 2000.0,
+            min_angle: 0.0,
+            max_angle: 180.0,
+            max_speed: 90.0,
+            initial: None,
+        }
+    }
+}
+
+impl ServoConfig {
+    pub fn pwm_channel(&self, name: &str) -> String {
+        self.channel.clone().unwrap_or_else(|| name.to_string())
+    }
+
+    fn clamp_angle(&self, angle: f32) -> f32 {
+        angle.max(self.min_angle).min(self.max_angle)
+    }
+
+    // Linear between the pulse limits
+    fn pulse_us(&self, angle: f32) -> f32 {
+        let range = self.max_angle - self.min_angle;
+        if range <= 0.0 {
+            return self.min_pulse_us;
+        }
+        let fraction = (self.clamp_angle(angle) - self.min_angle) / range;
+        self.min_pulse_us + fraction * (self.max_pulse_us - self.min_pulse_us)
+    }
+}
+
+// The PCA9685 splits every period of `frequency` into `STEPS`
+fn pulse_duty_cycle(pulse_us: f32, frequency: u16) -> u16 {
+    let duty_cycle = pulse_us * frequency as f32 * STEPS as f32 / 1_000_000.0;
+    (duty_cycle.round() as u16).min(STEPS - 1)
+}
+
+struct Servo {
+    config: ServoConfig,
+    channel: String,
+    // `None` until the servo has been driven, its position is unknown before that
+    angle: Option<f32>,
+    target: Option<f32>,
+    // degrees per second
+    speed: f32,
+}
+
+impl Servo {
+    fn status(&self) -> ServoStatus {
+        ServoStatus {
+            channel: self.channel.clone(),
+            angle: self.angle,
+            target: self.target,
+        }
+    }
+
+    // Moves the angle towards the target, returns true when the target has been reached
+    fn step(&mut self, dt: f32) -> bool {
+        let target = match self.target {
+            Some(target) => target,
+            None => return false,
+        };
+        let angle = self.angle.unwrap_or(target);
+        let max_step = self.speed * dt;
+
+        let next = if (target - angle).abs() <= max_step {
+            target
+        } else if target > angle {
+            angle + max_step
+        } else {
+            angle - max_step
+        };
+        let next = self.config.clamp_angle(next);
+        self.angle = Some(next);
+
+        if next == target {
+            self.target = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct ServoState {
+    servos: BTreeMap<String, Servo>,
+    pwm: SharedPwm,
+    tx: Arc<Mutex<Tx>>,
+}
+
+impl ServoState {
+    fn send(&self, event: Event) {
+        let tx = self.tx.lock().unwrap();
+        match tx.unbounded_send(TimedEvent::new(event)) {
+            Ok(_) => (),
+            Err(e) => println!("servo event send error = {:?}", e),
+        }
+    }
+
+    fn send_error(&self, message: String) {
+        self.send(Event::Error { message });
+    }
+
+    fn send_status(&self) {
+        let servos = self
+            .servos
+            .iter()
+            .map(|(name, servo)| (name.clone(), servo.status()))
+            .collect();
+        self.send(Event::Servos { servos });
+    }
+
+    fn set_pulse(&self, channel: &str, pulse_us: Option<f32>) {
+        let mut pwm = self.pwm.lock().unwrap();
+        let result = pwm.frequency(channel).and_then(|frequency| {
+            let duty_cycle = pulse_us.map_or(0, |pulse_us| pulse_duty_cycle(pulse_us, frequency));
+            pwm.set_duty_cycle(channel, duty_cycle)
+        });
+        if let Err(e) = result {
+            println!("Could not set servo channel {} = {:?}", channel, e);
+        }
+    }
+
+    // Writes the current angle of a servo, `None` stops the pulses
+    fn write(&self, name: &str) {
+        let servo = &self.servos[name];
+        let pulse = servo.angle.map(|angle| servo.config.pulse_us(angle));
+        self.set_pulse(&servo.channel, pulse);
+    }
+
+    fn servo(&mut self, name: &str) -> Option<&mut Servo> {
+        if !self.servos.contains_key(name) {
+            self.send_error(format!("No servo '{}'", name));
+        }
+        self.servos.get_mut(name)
+    }
+
+    fn is_moving(&self) -> bool {
+        self.servos.values().any(|servo| servo.target.is_some())
+    }
+
+    fn step(&mut self, dt: f32) {
+        let mut moved = Vec::new();
+        let mut arrived = false;
+        for (name, servo) in self.servos.iter_mut() {
+            if servo.target.is_some() {
+                arrived |= servo.step(dt);
+                moved.push(name.clone());
+            }
+        }
+
+        for name in &moved {
+            self.write(name);
+        }
+        if arrived {
+            self.send_status();
+        }
+    }
+}
+
+// Servos whose channel doesn't exist or is taken by the motors or another servo are left out,
+// as are servos which couldn't sweep
+fn servos(config: &Config, pwm: &SharedPwm) -> BTreeMap<String, Servo> {
+    let pwm = pwm.lock().unwrap();
+    let mut servos: BTreeMap<String, Servo> = BTreeMap::new();
+    for (name, servo_config) in &config.servos {
+        if servo_config.max_speed <= 0.0 {
+            println!("Servo '{}' needs a max_speed above 0", name);
+            continue;
+        }
+        let channel = servo_config.pwm_channel(name);
+        if !pwm.has_channel(&channel) || MOTOR_CHANNELS.contains(&channel.as_str()) {
+            println!("Servo '{}' can't use channel {}", name, channel);
+            continue;
+        }
+        if let Some((other, _)) = servos.iter().find(|(_, servo)| servo.channel == channel) {
+            println!(
+                "Servo '{}' can't use channel {}, it belongs to '{}'",
+                name, channel, other
+            );
+            continue;
+        }
+
+        servos.insert(
+            name.clone(),
+            Servo {
+                config: servo_config.clone(),
+                channel,
+                angle: None,
+                target: None,
+                speed: servo_config.max_speed,
+            },
+        );
+    }
+    servos
+}
+
+/// Drives the servos of pan/tilt mounts and the like on the free PCA9685 channels.
+pub struct ServoHandler {
+    rx_command: RxCommand,
+    state: Arc<Mutex<ServoState>>,
+}
+
+impl ServoHandler {
+    pub fn new(tx: Arc<Mutex<Tx>>, config: &Config, pwm: SharedPwm) -> (ServoHandler, TxCommand) {
+        let (tx_command, rx_command) = mpsc::unbounded();
+
+        let mut state = ServoState {
+            servos: servos(config, &pwm),
+            pwm,
+            tx,
+        };
+
+        let names: Vec<String> = state.servos.keys().cloned().collect();
+        for name in &names {
+            let initial = state.servos[name].config.initial;
+            if let Some(angle) = initial {
+                let servo = state.servos.get_mut(name).unwrap();
+                servo.angle = Some(servo.config.clamp_angle(angle));
+                state.write(name);
+            }
+        }
+        state.send_status();
+
+        (
+            ServoHandler {
+                rx_command,
+                state: Arc::new(Mutex::new(state)),
+            },
+            tx_command,
+        )
+    }
+
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        let state_command_arc = self.state.clone();
+        let command_handler = self
+            .rx_command
+            .for_each(move |command| {
+                let mut state = state_command_arc.lock().unwrap();
+                match command {
+                    ServoCommand::Set { name, angle } => {
+                        println!("Received servo set command ");
+                        if let Some(servo) = state.servo(&name) {
+                            servo.angle = Some(servo.config.clamp_angle(angle));
+                            servo.target = None;
+                        } else {
+                            return Ok(());
+                        }
+                        state.write(&name);
+                        state.send_status();
+                    }
+                    ServoCommand::Sweep { name, angle, speed } => {
+                        println!("Received servo sweep command ");
+                        // A servo which can't move would never reach its target
+                        if speed.map_or(false, |speed| speed <= 0.0) {
+                            state.send_error("Sweep speed has to be above 0".to_string());
+                            return Ok(());
+                        }
+                        if let Some(servo) = state.servo(&name) {
+                            let max_speed = servo.config.max_speed;
+                            servo.target = Some(servo.config.clamp_angle(angle));
+                            servo.speed = speed.map_or(max_speed, |speed| speed.min(max_speed));
+                        } else {
+                            return Ok(());
+                        }
+                        state.send_status();
+                    }
+                    ServoCommand::Stop { name } => {
+                        println!("Received servo stop command ");
+                        if let Some(servo) = state.servo(&name) {
+                            servo.target = None;
+                        } else {
+                            return Ok(());
+                        }
+                        state.send_status();
+                    }
+                    ServoCommand::Off { name } => {
+                        println!("Received servo off command ");
+                        if let Some(servo) = state.servo(&name) {
+                            servo.angle = None;
+                            servo.target = None;
+                        } else {
+                            return Ok(());
+                        }
+                        state.write(&name);
+                        state.send_status();
+                    }
+                }
+
+                Ok(())
+            })
+            .map_err(|err| {
+                println!("servo command error = {:?}", err);
+            });
+
+        let state_sweep_arc = self.state.clone();
+        let sweeper = Interval::new(Instant::now(), Duration::from_millis(STEP_MS))
+            .for_each(move |_| {
+                let mut state = state_sweep_arc.lock().unwrap();
+                if state.is_moving() {
+                    state.step(STEP_MS as f32 / 1000.0);
+                }
+                Ok(())
+            })
+            .map_err(|e| print!("interval errored; err={:?}", e));
+
+        command_handler.join(sweeper).map(|_| ())
+    }
+}
@@ -0,0 +1,46 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::event::{Event, MotorStatus};
+use crate::motor_handler::MotorStatusHandle;
+
+/// Latest event of every topic, so that clients don't have to wait
+/// for the next sensor reading to know the rover's state.
+pub struct StateCache {
+    events: BTreeMap<&'static str, String>,
+    motor: MotorStatusHandle,
+}
+
+impl StateCache {
+    pub fn new(motor: MotorStatusHandle) -> StateCache {
+        StateCache {
+            events: BTreeMap::new(),
+            motor,
+        }
+    }
+
+    pub fn update(&mut self, topic: &'static str, event_json: String) {
+        self.events.insert(topic, event_json);
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&String> {
+        self.events.get(topic)
+    }
+
+    pub fn motor_status(&self) -> MotorStatus {
+        self.motor.get()
+    }
+
+    pub fn snapshot(&self) -> Event {
+        let events = self
+            .events
+            .iter()
+            .map(|(topic, json)| (*topic, serde_json::from_str(json).unwrap_or(Value::Null)))
+            .collect();
+
+        Event::State {
+            events,
+            motor: self.motor.get(),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::io;
+use std::path::PathBuf;
+
+use crate::command::MotorCommand;
+use crate::event::EncodersSnapshot;
+use crate::persist::{data_path, load_json, save_json};
+
+const TRAJECTORIES_DIR: &str = "trajectories";
+
+// Names end up in file paths, so they can't contain separators or dots
+fn path(name: &str) -> io::Result<PathBuf> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "trajectory names may only contain letters, digits, '-' and '_'",
+        ));
+    }
+
+    Ok(data_path(TRAJECTORIES_DIR).join(format!("{}.json", name)))
+}
+
+/// One executed motion command and how far the wheels actually turned during it.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Segment {
+    // Kept for reference, the replay follows the measured ticks
+    pub command: MotorCommand,
+    // Signed encoder counts, including rolling out after the motion
+    pub left: isize,
+    pub right: isize,
+    // ms during which the wheels were turning
+    pub duration: isize,
+}
+
+impl Segment {
+    /// Signed ticks of both wheels in the order they have to be driven,
+    /// a reversed segment drives the same path backward.
+    pub fn ticks(&self, reverse: bool) -> (isize, isize) {
+        if reverse {
+            (-self.left, -self.right)
+        } else {
+            (self.left, self.right)
+        }
+    }
+
+    /// Average speed of the faster wheel in ticks per second.
+    pub fn speed(&self) -> f32 {
+        if self.duration <= 0 {
+            return 0.0;
+        }
+        self.left.abs().max(self.right.abs()) as f32 * 1000.0 / self.duration as f32
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct Trajectory {
+    pub segments: Vec<Segment>,
+}
+
+impl Trajectory {
+    pub fn load(name: &str) -> io::Result<Trajectory> {
+        load_json(path(name)?)
+    }
+
+    pub fn save(&self, name: &str) -> io::Result<()> {
+        save_json(path(name)?, self)
+    }
+
+    /// Segments in the order they have to be driven.
+    pub fn segments(&self, reverse: bool) -> Vec<&Segment> {
+        if reverse {
+            self.segments.iter().rev().collect()
+        } else {
+            self.segments.iter().collect()
+        }
+    }
+}
+
+// Experiments don't follow a path, they end the segment before them without starting one
+fn is_recorded(command: &MotorCommand) -> bool {
+    match command {
+        MotorCommand::Move { .. }
+        | MotorCommand::MoveDistance { .. }
+        | MotorCommand::Rotate { .. }
+        | MotorCommand::Arc { .. }
+        | MotorCommand::GoTo { .. }
+        | MotorCommand::Drive { .. }
+        | MotorCommand::Wheels { .. }
+        | MotorCommand::WheelTicks { .. } => true,
+        _ => false,
+    }
+}
+
+/// Splits the encoder counts into one segment per started motion.
+pub struct Recorder {
+    name: String,
+    trajectory: Trajectory,
+    current: Option<Segment>,
+}
+
+impl Recorder {
+    pub fn new(name: &str) -> io::Result<Recorder> {
+        path(name)?;
+        Ok(Recorder {
+            name: name.to_string(),
+            trajectory: Trajectory::default(),
+            current: None,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn close(&mut self) {
+        if let Some(segment) = self.current.take() {
+            // A command which didn't move anything can't be replayed
+            if segment.left != 0 || segment.right != 0 {
+                self.trajectory.segments.push(segment);
+            }
+        }
+    }
+
+    pub fn begin(&mut self, command: &MotorCommand) {
+        self.close();
+        if is_recorded(command) {
+            self.current = Some(Segment {
+                command: command.clone(),
+                left: 0,
+                right: 0,
+                duration: 0,
+            });
+        }
+    }
+
+    pub fn add(&mut self, encoders: &EncodersSnapshot) {
+        if let Some(segment) = self.current.as_mut() {
+            segment.left += encoders.left;
+            segment.right += encoders.right;
+            if encoders.left != 0 || encoders.right != 0 {
+                segment.duration += encoders.duration;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> (String, Trajectory) {
+        self.close();
+        (self.name, self.trajectory)
+    }
+}